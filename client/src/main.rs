@@ -5,17 +5,24 @@ extern crate yew;
 extern crate shared;
 #[macro_use]
 extern crate stdweb;
-#[macro_use]
 extern crate failure;
+extern crate pulldown_cmark;
 extern crate serde;
 extern crate serde_json;
 
 use failure::Error;
 use shared::*;
-use yew::format::Json;
+use std::cmp::Ordering;
+use std::rc::Rc;
+use stdweb::web::{document, INode};
+use yew::format::{Json, Nothing};
+use yew::html::ChangeData;
 use yew::prelude::*;
 use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use yew::services::storage::{Area, StorageService};
+use yew::services::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
 use yew::services::ConsoleService;
+use yew::virtual_dom::VNode;
 
 fn is_keyword(ident: &str) -> bool {
     match ident {
@@ -147,24 +154,367 @@ fn hightlight<T: Component>(src: &str) -> Html<T> {
 
 struct Node {
     code: String,
-    result: Option<NodeResult>,
+    output: Option<NodeOutput>,
+    /// The compiler diagnostics pointing into this node
+    diagnostics: Vec<Diagnostic>,
+    /// How a table in the output is sorted and paged
+    table: TableView,
+    /// The `Bench` result of the run before the latest one, to compare with
+    previous_bench: Option<NodeResult>,
+}
+
+/// Rows of a table shown at a time.
+const TABLE_PAGE_SIZE: usize = 20;
+
+#[derive(Default)]
+struct TableView {
+    /// The column sorted by, and whether it is sorted in descending order
+    sort: Option<(usize, bool)>,
+    page: usize,
+}
+
+pub enum TableMsg {
+    Sort(usize),
+    Page(usize),
+}
+
+impl TableView {
+    fn update(&mut self, msg: TableMsg) {
+        match msg {
+            TableMsg::Sort(column) => {
+                self.sort = match self.sort {
+                    Some((sorted, descending)) if sorted == column => Some((column, !descending)),
+                    _ => Some((column, false)),
+                };
+                self.page = 0;
+            }
+            TableMsg::Page(page) => self.page = page,
+        }
+    }
+
+    fn view<F>(&self, columns: &[String], rows: &[Vec<String>], table: F) -> Html<Model>
+    where
+        F: 'static + Fn(TableMsg) -> Msg,
+    {
+        let table = Rc::new(table);
+
+        let mut rows = rows.iter().collect::<Vec<_>>();
+        if let Some((column, descending)) = self.sort {
+            // Numbers are sorted by their value, everything else as text.
+            // Rows too short to have the column come first.
+            rows.sort_by(|a, b| {
+                let ordering = match (a.get(column), b.get(column)) {
+                    (Some(a), Some(b)) => match (a.parse::<f64>(), b.parse::<f64>()) {
+                        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                        _ => a.cmp(b),
+                    },
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                };
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+
+        let pages = (rows.len() + TABLE_PAGE_SIZE - 1) / TABLE_PAGE_SIZE;
+        let page = self.page.min(pages.saturating_sub(1));
+        let start = page * TABLE_PAGE_SIZE;
+        let end = (start + TABLE_PAGE_SIZE).min(rows.len());
+
+        let headers = columns.iter().enumerate().map(|(i, column)| {
+            let table = table.clone();
+            let arrow = match self.sort {
+                Some((sorted, false)) if sorted == i => " ▲",
+                Some((sorted, true)) if sorted == i => " ▼",
+                _ => "",
+            };
+            html!{
+                <th onclick=|_| table(TableMsg::Sort(i)),>{format!("{}{}", column, arrow)}</th>
+            }
+        });
+        let body = rows[start..end].iter().map(|row| html!{
+            <tr>{for row.iter().map(|cell| html!{<td>{cell}</td>})}</tr>
+        });
+        let pagination = if pages > 1 {
+            let (previous, next) = (table.clone(), table.clone());
+            html!{
+                <div class="pagination",>
+                    <button
+                        disabled=page == 0,
+                        onclick=|_| previous(TableMsg::Page(page.saturating_sub(1))),
+                    >{"Previous"}</button>
+                    <span>{format!("{}–{} of {}", start + 1, end, rows.len())}</span>
+                    <button
+                        disabled=page + 1 >= pages,
+                        onclick=|_| next(TableMsg::Page(page + 1)),
+                    >{"Next"}</button>
+                </div>
+            }
+        } else {
+            html!{{""}}
+        };
+
+        html!{
+            <div>
+                <table class="rich-table",>
+                    <thead>
+                        <tr>{for headers}</tr>
+                    </thead>
+                    <tbody>{for body}</tbody>
+                </table>
+                {pagination}
+            </div>
+        }
+    }
+}
+
+/// The byte offset of `position` in `src`.
+fn offset(src: &str, position: LineColumn) -> usize {
+    let line_start = src
+        .split('\n')
+        .take(position.line - 1)
+        .map(|line| line.len() + 1)
+        .sum::<usize>()
+        .min(src.len());
+    src[line_start..]
+        .char_indices()
+        .map(|(i, _)| line_start + i)
+        .chain(Some(src.len()))
+        .nth(position.column - 1)
+        .unwrap_or_else(|| src.len())
 }
 
 impl Node {
     fn new(code: String) -> Node {
-        Node { code, result: None }
+        Node {
+            code,
+            output: None,
+            diagnostics: vec![],
+            table: TableView::default(),
+            previous_bench: None,
+        }
+    }
+
+    fn set_output(&mut self, output: Option<NodeOutput>) {
+        self.output = output;
+        self.table = TableView::default();
     }
 
-    fn view<F, G>(&self, change: F, run: G) -> Html<Model>
+    /// Turns a truncated result back into the result it was cut from, once
+    /// all of it is fetched.
+    fn restore_result(&mut self) {
+        let restored = match self.output.as_ref().map(|output| &output.result) {
+            Some(NodeResult::Truncated {
+                preview,
+                full_len,
+                of,
+                ..
+            }) if preview.len() == *full_len => of.restore(preview.clone()),
+            _ => None,
+        };
+        if let (Some(output), Some(result)) = (self.output.as_mut(), restored) {
+            output.result = result;
+        }
+    }
+
+    /// The highlighted code, with the spans of the diagnostics underlined.
+    fn highlighted(&self) -> Html<Model> {
+        let mut spans = self
+            .diagnostics
+            .iter()
+            .filter_map(|diagnostic| {
+                let span = diagnostic.span.as_ref()?;
+                let class = if diagnostic.level == "warning" {
+                    "diagnostic warning"
+                } else {
+                    "diagnostic error"
+                };
+                Some((offset(&self.code, span.start), offset(&self.code, span.end), class))
+            })
+            .filter(|(start, end, _)| start < end)
+            .collect::<Vec<_>>();
+        spans.sort();
+
+        let mut parts = vec![];
+        let mut at = 0;
+        for (start, end, class) in spans {
+            // Overlapping spans are underlined by the first of them
+            if start < at {
+                continue;
+            }
+            parts.push(hightlight(&self.code[at..start]));
+            parts.push(html!{
+                <span class=class,>{hightlight(&self.code[start..end])}</span>
+            });
+            at = end;
+        }
+        parts.push(hightlight(&self.code[at..]));
+
+        html!{{for parts}}
+    }
+
+    fn view<F, G, H, I, J>(
+        &self,
+        change: F,
+        run: G,
+        table: H,
+        more: I,
+        more_stdout: J,
+    ) -> Html<Model>
     where
         F: 'static + Fn(String) -> Msg,
         G: 'static + Fn() -> Msg,
+        H: 'static + Fn(TableMsg) -> Msg,
+        I: 'static + Fn() -> Msg,
+        J: 'static + Fn() -> Msg,
     {
-        let output = match &self.result {
+        let output = match self.output.as_ref().map(|output| &output.result) {
             None => html!{{""}},
             Some(NodeResult::None) => html!{<span class="faded-text",>{"none"}</span>},
-            Some(NodeResult::String(s)) => html!{{s}},
+            Some(NodeResult::String {
+                value,
+                format,
+                type_name,
+            }) => html!{
+                <span class=match format {
+                    Format::Debug => "debug",
+                    Format::PrettyDebug => "pretty-debug",
+                    Format::Display => "display",
+                },>{value}</span>
+                <span class="type-name",>{format!(" : {}", type_name)}</span>
+            },
+            Some(NodeResult::Html(html)) | Some(NodeResult::Svg(html)) => raw_html(html),
+            Some(NodeResult::Png(png)) => html!{
+                <img src=format!("data:image/png;base64,{}", png),/>
+            },
+            Some(NodeResult::Markdown(text)) => markdown(text),
+            Some(NodeResult::Table { columns, rows }) => self.table.view(columns, rows, table),
+            Some(NodeResult::Bench {
+                mean,
+                median,
+                stddev,
+                iterations,
+            }) => {
+                let previous = match &self.previous_bench {
+                    Some(NodeResult::Bench {
+                        mean,
+                        median,
+                        stddev,
+                        iterations,
+                    }) => Some((*mean, *median, *stddev, *iterations)),
+                    _ => None,
+                };
+                let time_row = |name: &str, current: f64, previous: Option<f64>| {
+                    let (previous, change) = match previous {
+                        // Nothing to compare with when the previous run took no time
+                        Some(previous) if previous == 0.0 => (format_ns(previous), html!{{""}}),
+                        Some(previous) => {
+                            let change = (current - previous) / previous * 100.0;
+                            let class = if change > 0.0 { "slower" } else { "faster" };
+                            (
+                                format_ns(previous),
+                                html!{<span class=class,>{format!("{:+.1}%", change)}</span>},
+                            )
+                        }
+                        None => (String::new(), html!{{""}}),
+                    };
+                    html!{
+                        <tr>
+                            <th>{name}</th>
+                            <td>{format_ns(current)}</td>
+                            <td>{previous}</td>
+                            <td>{change}</td>
+                        </tr>
+                    }
+                };
+                html!{
+                    <table class="rich-table bench",>
+                        <thead>
+                            <tr>
+                                <th></th>
+                                <th>{"This run"}</th>
+                                <th>{"Previous run"}</th>
+                                <th>{"Change"}</th>
+                            </tr>
+                        </thead>
+                        <tbody>
+                            {time_row("Mean", *mean, previous.map(|previous| previous.0))}
+                            {time_row("Median", *median, previous.map(|previous| previous.1))}
+                            {time_row("Std. dev.", *stddev, previous.map(|previous| previous.2))}
+                            <tr>
+                                <th>{"Iterations"}</th>
+                                <td>{iterations}</td>
+                                <td>{previous.map(|previous| previous.3.to_string()).unwrap_or_default()}</td>
+                                <td></td>
+                            </tr>
+                        </tbody>
+                    </table>
+                }
+            }
+            Some(NodeResult::Truncated {
+                preview, full_len, ..
+            }) => html!{
+                <span class="truncated",>{preview}</span>
+                {show_more(preview.len(), *full_len, more)}
+            },
+            Some(NodeResult::Panic {
+                message,
+                node,
+                location,
+                backtrace,
+            }) => {
+                let location = match (node, location) {
+                    (Some(node), Some(location)) => format!(
+                        " in node {} at {}:{}",
+                        node, location.line, location.column
+                    ),
+                    _ => String::new(),
+                };
+                html!{
+                    <span class="panic",>
+                        {format!("panicked{}: {}", location, message)}
+                        <pre class="backtrace",>{backtrace.as_ref().map_or("", String::as_str)}</pre>
+                    </span>
+                }
+            }
+        };
+        let stdout = match &self.output {
+            Some(output) if output.stdout != "" || output.stdout_truncated.is_some() => html!{
+                <pre class="node-stdout",>
+                    <code>{&output.stdout}</code>
+                    {match &output.stdout_truncated {
+                        Some(truncated) => {
+                            show_more(output.stdout.len(), truncated.full_len, more_stdout)
+                        }
+                        None => html!{{""}},
+                    }}
+                </pre>
+            },
+            _ => html!{{""}},
         };
+        let footer = match self.output.as_ref().and_then(|output| output.time_ns) {
+            Some(time_ns) => html!{
+                <div class="node-footer",>{format!("Ran in {}", format_ns(time_ns as f64))}</div>
+            },
+            None => html!{{""}},
+        };
+        let diagnostics = self.diagnostics.iter().map(|diagnostic| {
+            let code = diagnostic
+                .code
+                .as_ref()
+                .map(|code| format!("[{}]", code))
+                .unwrap_or_default();
+            let location = diagnostic
+                .span
+                .as_ref()
+                .map(|span| format!(" ({}:{})", span.start.line, span.start.column))
+                .unwrap_or_default();
+            html!{
+                <li>{format!("{}{}: {}{}", diagnostic.level, code, diagnostic.message, location)}</li>
+            }
+        });
 
         let rows = self
             .code
@@ -190,36 +540,142 @@ impl Node {
                         rows=rows,
                     />
                     <code>
-                        {hightlight(&self.code)}
+                        {self.highlighted()}
                     </code>
                 </div>
                 <code class="output",>{output}</code>
+                {stdout}
+                {footer}
+                <ul class="diagnostics",>{for diagnostics}</ul>
             </div>
         }
     }
 }
+/// A duration in nanoseconds, in the largest unit it has a whole one of.
+fn format_ns(ns: f64) -> String {
+    if ns >= 1e9 {
+        format!("{:.2} s", ns / 1e9)
+    } else if ns >= 1e6 {
+        format!("{:.2} ms", ns / 1e6)
+    } else if ns >= 1e3 {
+        format!("{:.2} µs", ns / 1e3)
+    } else {
+        format!("{:.2} ns", ns)
+    }
+}
+
+/// A button fetching more of a truncated text, of which `len` bytes are
+/// shown so far.
+fn show_more<F>(len: usize, full_len: usize, more: F) -> Html<Model>
+where
+    F: 'static + Fn() -> Msg,
+{
+    if len >= full_len {
+        return html!{{""}};
+    }
+    html!{
+        <button class="show-more", onclick=|_| more(),>
+            {format!("Show more ({} of {} bytes)", len, full_len)}
+        </button>
+    }
+}
+
+/// Renders `html` as it is, for the rich output of nodes.
+fn raw_html<T: Component>(html: &str) -> Html<T> {
+    let element = document()
+        .create_element("div")
+        .expect("failed to create element");
+    js!{ @{&element}.innerHTML = @{html}; };
+    VNode::VRef(element.as_node().clone())
+}
+
+fn markdown<T: Component>(markdown: &str) -> Html<T> {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+    raw_html(&html)
+}
 
 enum ExecutionState {
     Idle,
-    Running(FetchTask),
+    Running {
+        task: WebSocketTask,
+        /// The latest line of build output
+        status: String,
+        /// Everything printed by the program so far
+        output: String,
+        /// The id to cancel the execution with, once the server sent it
+        execution: Option<String>,
+    },
     Done(ExecutionResponse),
+    Disconnected,
+    /// The server sent an event which could not be read
+    BadEvent(String),
+}
+
+/// A text which can be cut short by the server.
+#[derive(Clone, Copy)]
+pub enum TruncatedText {
+    /// The result of a node
+    Node(usize),
+    /// What a node printed
+    NodeStdout(usize),
+    Stdout,
 }
 
+/// Where the ids of the sessions are kept in the local storage.
+const SESSIONS_KEY: &str = "repl.sessions";
+
 pub struct Model {
     console: ConsoleService,
-    web: FetchService,
-    callback: Callback<Result<ExecutionResponse, Error>>,
+    ws: WebSocketService,
+    fetch: FetchService,
+    link: ComponentLink<Model>,
+    /// Fetching more of a truncated text
+    fetch_task: Option<FetchTask>,
+    /// Listing, loading, creating or deleting sessions
+    session_task: Option<FetchTask>,
+    /// Stopping the running execution
+    cancel_task: Option<FetchTask>,
+    callback: Callback<Result<ExecutionEvent, Error>>,
+    notification: Callback<WebSocketStatus>,
     nodes: Vec<Node>,
     state: ExecutionState,
+    persistent: bool,
+    build: BuildOptions,
+    /// Remembers the ids of the sessions, which the server only lists to
+    /// those knowing them.
+    storage: StorageService,
+    sessions: Vec<SessionInfo>,
+    /// The id of the session the nodes run in
+    session: Option<String>,
 }
 
 pub enum Msg {
     Noop,
     Bulk(Vec<Msg>),
     Run,
+    Stop,
+    Stopped(Result<bool, Error>),
+    Connected,
+    Disconnected,
+    Event(ExecutionEvent),
+    BadEvent(Error),
     AddNode(String),
     Response(ExecutionResponse),
     ChangeNode(usize, String),
+    Table(usize, TableMsg),
+    ShowMore(TruncatedText),
+    Chunk(TruncatedText, Result<ChunkResponse, Error>),
+    TogglePersistent,
+    SetProfile(Profile),
+    SetEdition(Option<String>),
+    Sessions(Result<Vec<SessionInfo>, Error>),
+    SelectSession(Option<String>),
+    SessionLoaded(Result<SessionResponse, Error>),
+    NewSession,
+    SessionCreated(Result<SessionInfo, Error>),
+    DeleteSession,
+    SessionDeleted(Result<bool, Error>),
 }
 
 impl Component for Model {
@@ -227,16 +683,32 @@ impl Component for Model {
     type Properties = ();
 
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
-        Model {
+        let mut model = Model {
             console: ConsoleService::new(),
-            web: FetchService::new(),
-            callback: link.send_back(|res: Result<_, _>| Msg::Response(res.unwrap())),
-            nodes: vec![Node {
-                code: "let x = 12;\nx + 21".to_string(),
-                result: None,
-            }],
+            ws: WebSocketService::new(),
+            fetch: FetchService::new(),
+            fetch_task: None,
+            session_task: None,
+            cancel_task: None,
+            callback: link.send_back(|res: Result<_, _>| match res {
+                Ok(event) => Msg::Event(event),
+                Err(error) => Msg::BadEvent(error),
+            }),
+            notification: link.send_back(|status| match status {
+                WebSocketStatus::Opened => Msg::Connected,
+                WebSocketStatus::Closed | WebSocketStatus::Error => Msg::Disconnected,
+            }),
+            nodes: vec![Node::new("let x = 12;\nx + 21".to_string())],
             state: ExecutionState::Idle,
-        }
+            persistent: false,
+            build: BuildOptions::default(),
+            storage: StorageService::new(Area::Local),
+            sessions: vec![],
+            session: None,
+            link,
+        };
+        model.fetch_sessions();
+        model
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
@@ -248,56 +720,432 @@ impl Component for Model {
             },
             Msg::Run => {
                 self.console.log("Run!");
-                let params = ExecutionParameters {
-                    nodes: self
-                        .nodes
-                        .iter()
-                        .map(|node| ExecutionNode {
-                            content: node.code.clone(),
-                        })
-                        .collect(),
+                for node in &mut self.nodes {
+                    node.diagnostics.clear();
+                    if let Some(output) = &node.output {
+                        if let NodeResult::Bench { .. } = output.result {
+                            node.previous_bench = Some(output.result.clone());
+                        }
+                    }
+                }
+                let task = self.ws.connect(
+                    "ws://localhost:8080/stream",
+                    self.callback.clone(),
+                    self.notification.clone(),
+                );
+                self.state = ExecutionState::Running {
+                    task,
+                    status: String::new(),
+                    output: String::new(),
+                    execution: None,
                 };
-                let task = run(&params, &mut self.web, self.callback.clone());
-                self.state = ExecutionState::Running(task);
+            }
+            Msg::Stop => {
+                let id = match &self.state {
+                    ExecutionState::Running {
+                        execution: Some(id),
+                        ..
+                    } => id.clone(),
+                    _ => return false,
+                };
+                let request = Request::post(format!("http://localhost:8080/cancel/{}", id))
+                    .body(Nothing)
+                    .expect("failed to build request");
+                let callback = self.link.send_back(
+                    |response: Response<Json<Result<bool, Error>>>| {
+                        let (_, Json(cancelled)) = response.into_parts();
+                        Msg::Stopped(cancelled)
+                    },
+                );
+                self.cancel_task = Some(self.fetch.fetch(request, callback));
+            }
+            Msg::Stopped(cancelled) => {
+                self.cancel_task = None;
+                // The execution ends with a `Cancelled` error, unless it was done already
+                match cancelled {
+                    Ok(true) => {}
+                    Ok(false) => self.console.log("The execution was not running any more"),
+                    Err(error) => self.console.log(&format!("Failed to stop the execution: {}", error)),
+                }
+            }
+            Msg::Connected => {
+                let params = self.parameters();
+                if let ExecutionState::Running { task, .. } = &mut self.state {
+                    task.send(Json(&params));
+                }
+            }
+            Msg::Disconnected => {
+                // The socket is closed by the server after the last event
+                if let ExecutionState::Running { .. } = self.state {
+                    self.state = ExecutionState::Disconnected;
+                }
+            }
+            Msg::Event(ExecutionEvent::Done(res)) => {
+                self.update(Msg::Response(res));
+            }
+            Msg::Event(ExecutionEvent::Node { node, output }) => {
+                if let Some(node) = self.nodes.get_mut(node) {
+                    node.set_output(Some(output));
+                }
+            }
+            Msg::Event(event) => {
+                if let ExecutionState::Running {
+                    status,
+                    output,
+                    execution,
+                    ..
+                } = &mut self.state
+                {
+                    match event {
+                        ExecutionEvent::Started { execution: id } => *execution = Some(id),
+                        ExecutionEvent::Queued { position: 0 } => {
+                            *status = "Waiting for a build directory, next in line".to_string()
+                        }
+                        ExecutionEvent::Queued { position } => {
+                            *status = format!("Waiting for a build directory, {} ahead", position)
+                        }
+                        ExecutionEvent::Build(line) => *status = line.trim().to_string(),
+                        ExecutionEvent::Stdout(line) | ExecutionEvent::Stderr(line) => {
+                            output.push_str(&line);
+                            output.push('\n');
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Msg::BadEvent(error) => {
+                // Dropping the socket ends the execution
+                if let ExecutionState::Running { .. } = self.state {
+                    self.state = ExecutionState::BadEvent(error.to_string());
+                }
             }
             Msg::AddNode(code) => {
                 self.nodes.push(Node::new(code));
             }
             Msg::Response(Ok(res)) => {
-                for (node, res) in self.nodes.iter_mut().zip(&res.nodes) {
-                    node.result = Some(res.clone());
+                // Nodes after one exiting the program have no output
+                for (i, node) in self.nodes.iter_mut().enumerate() {
+                    node.set_output(res.nodes.get(i).cloned());
                 }
                 self.state = ExecutionState::Done(Ok(res));
             }
             Msg::Response(Err(e)) => {
+                match &e {
+                    XXError::BuildError { diagnostics, .. } => for diagnostic in diagnostics {
+                        let node = diagnostic.node.and_then(|node| self.nodes.get_mut(node));
+                        if let Some(node) = node {
+                            node.diagnostics.push(diagnostic.clone());
+                        }
+                    },
+                    XXError::ParseNode {
+                        error, node: index, span, ..
+                    } => {
+                        if let Some(node) = self.nodes.get_mut(*index) {
+                            node.diagnostics.push(Diagnostic {
+                                level: "error".to_string(),
+                                message: error.clone(),
+                                code: None,
+                                node: Some(*index),
+                                span: Some(*span),
+                                suggestions: vec![],
+                            });
+                        }
+                        // The node is rendered by the time this runs
+                        js!{
+                            setTimeout(function() {
+                                var node = document.querySelectorAll(".nodes .node")[@{*index as u32}];
+                                var error = node && node.querySelector(".diagnostic");
+                                if (error) {
+                                    error.scrollIntoView({ block: "center" });
+                                }
+                            }, 0);
+                        }
+                    }
+                    // Deleted while idle, so the nodes run without a session from now on
+                    XXError::UnknownSession { .. } => {
+                        self.session = None;
+                        self.fetch_sessions();
+                    }
+                    _ => {}
+                }
                 self.state = ExecutionState::Done(Err(e));
             }
             Msg::ChangeNode(i, value) => {
                 self.nodes[i].code = value;
             }
+            Msg::Table(i, msg) => {
+                self.nodes[i].table.update(msg);
+            }
+            Msg::ShowMore(text) => {
+                let (handle, offset) = match self.truncated(text) {
+                    Some((preview, truncated)) => (truncated.handle, preview.len()),
+                    None => return false,
+                };
+                let request = Request::get(format!(
+                    "http://localhost:8080/output/{}?offset={}",
+                    handle, offset
+                )).body(Nothing)
+                    .expect("failed to build request");
+                let callback = self.link.send_back(
+                    move |response: Response<Json<Result<ChunkResponse, Error>>>| {
+                        let (_, Json(chunk)) = response.into_parts();
+                        Msg::Chunk(text, chunk)
+                    },
+                );
+                self.fetch_task = Some(self.fetch.fetch(request, callback));
+            }
+            Msg::Chunk(text, chunk) => {
+                self.fetch_task = None;
+                match chunk {
+                    Ok(Some(chunk)) => {
+                        // Only a chunk following what is shown so far is appended
+                        if let Some((preview, _)) = self.truncated_mut(text) {
+                            if preview.len() == chunk.offset {
+                                preview.push_str(&chunk.text);
+                            }
+                        }
+                        if let TruncatedText::Node(i) = text {
+                            if let Some(node) = self.nodes.get_mut(i) {
+                                node.restore_result();
+                            }
+                        }
+                    }
+                    Ok(None) => self.console.log("The output is not kept by the server any more"),
+                    Err(error) => self.console.log(&format!("Failed to fetch the output: {}", error)),
+                }
+            }
+            Msg::TogglePersistent => {
+                self.persistent = !self.persistent;
+            }
+            Msg::SetProfile(profile) => {
+                self.build.profile = profile;
+            }
+            Msg::SetEdition(edition) => {
+                self.build.edition = edition;
+            }
+            Msg::Sessions(sessions) => {
+                self.session_task = None;
+                match sessions {
+                    Ok(sessions) => {
+                        // Forgets the sessions which are gone
+                        self.sessions = sessions;
+                        self.remember_sessions();
+                    }
+                    Err(error) => self.console.log(&format!("Failed to list the sessions: {}", error)),
+                }
+            }
+            Msg::SelectSession(None) => {
+                self.session = None;
+            }
+            Msg::SelectSession(Some(id)) => {
+                let request = Request::get(format!("http://localhost:8080/sessions/{}", id))
+                    .body(Nothing)
+                    .expect("failed to build request");
+                let callback = self.link.send_back(
+                    |response: Response<Json<Result<SessionResponse, Error>>>| {
+                        let (_, Json(notebook)) = response.into_parts();
+                        Msg::SessionLoaded(notebook)
+                    },
+                );
+                self.session_task = Some(self.fetch.fetch(request, callback));
+            }
+            Msg::SessionLoaded(notebook) => {
+                self.session_task = None;
+                match notebook {
+                    Ok(Some(notebook)) => {
+                        self.session = Some(notebook.session.id);
+                        // A new session has no notebook yet, and keeps the nodes shown
+                        if !notebook.nodes.is_empty() {
+                            self.nodes = notebook
+                                .nodes
+                                .into_iter()
+                                .map(|node| Node::new(node.content))
+                                .collect();
+                            self.state = ExecutionState::Idle;
+                        }
+                    }
+                    Ok(None) => {
+                        self.console.log("The session does not exist any more");
+                        self.session = None;
+                        self.fetch_sessions();
+                    }
+                    Err(error) => self.console.log(&format!("Failed to load the session: {}", error)),
+                }
+            }
+            Msg::NewSession => {
+                let new = NewSession {
+                    name: format!("Session {}", self.sessions.len() + 1),
+                };
+                let request = Request::post("http://localhost:8080/sessions")
+                    .header("Content-Type", "application/json")
+                    .body(Json(&new))
+                    .expect("failed to build request");
+                let callback = self.link.send_back(
+                    |response: Response<Json<Result<SessionInfo, Error>>>| {
+                        let (_, Json(session)) = response.into_parts();
+                        Msg::SessionCreated(session)
+                    },
+                );
+                self.session_task = Some(self.fetch.fetch(request, callback));
+            }
+            Msg::SessionCreated(session) => {
+                self.session_task = None;
+                match session {
+                    Ok(session) => {
+                        self.session = Some(session.id.clone());
+                        self.sessions.push(session);
+                        self.remember_sessions();
+                    }
+                    Err(error) => self.console.log(&format!("Failed to create a session: {}", error)),
+                }
+            }
+            Msg::DeleteSession => {
+                let id = match &self.session {
+                    Some(id) => id.clone(),
+                    None => return false,
+                };
+                let request = Request::delete(format!("http://localhost:8080/sessions/{}", id))
+                    .body(Nothing)
+                    .expect("failed to build request");
+                let callback = self.link.send_back(
+                    |response: Response<Json<Result<bool, Error>>>| {
+                        let (_, Json(deleted)) = response.into_parts();
+                        Msg::SessionDeleted(deleted)
+                    },
+                );
+                self.session_task = Some(self.fetch.fetch(request, callback));
+            }
+            Msg::SessionDeleted(deleted) => {
+                self.session_task = None;
+                if let Err(error) = deleted {
+                    self.console.log(&format!("Failed to delete the session: {}", error));
+                }
+                self.session = None;
+                self.fetch_sessions();
+            }
         }
         true
     }
 }
 
-fn run(
-    execution_params: &ExecutionParameters,
-    web: &mut FetchService,
-    callback: Callback<Result<ExecutionResponse, Error>>,
-) -> FetchTask {
-    let handler = move |response: Response<Json<Result<ExecutionResponse, Error>>>| {
-        let (meta, Json(data)) = response.into_parts();
-        if meta.status.is_success() {
-            callback.emit(data)
-        } else {
-            callback.emit(Err(format_err!("Nooooo!")))
+impl Model {
+    fn fetch_sessions(&mut self) {
+        let ids = match self.storage.restore(SESSIONS_KEY) {
+            Json(Ok(ids)) => ids,
+            Json(Err(_)) => Vec::<String>::new(),
+        };
+        let request = Request::post("http://localhost:8080/sessions/lookup")
+            .header("Content-Type", "application/json")
+            .body(Json(&ids))
+            .expect("failed to build request");
+        let callback = self.link.send_back(
+            |response: Response<Json<Result<Vec<SessionInfo>, Error>>>| {
+                let (_, Json(sessions)) = response.into_parts();
+                Msg::Sessions(sessions)
+            },
+        );
+        self.session_task = Some(self.fetch.fetch(request, callback));
+    }
+
+    fn remember_sessions(&mut self) {
+        let ids = self
+            .sessions
+            .iter()
+            .map(|session| session.id.clone())
+            .collect::<Vec<_>>();
+        self.storage.store(SESSIONS_KEY, Json(&ids));
+    }
+
+    /// What is shown of a truncated text so far, and where to fetch the rest
+    /// from.
+    fn truncated(&self, text: TruncatedText) -> Option<(&String, Truncated)> {
+        match text {
+            TruncatedText::Node(i) => match &self.nodes.get(i)?.output.as_ref()?.result {
+                NodeResult::Truncated {
+                    preview,
+                    full_len,
+                    handle,
+                    ..
+                } => Some((
+                    preview,
+                    Truncated {
+                        full_len: *full_len,
+                        handle: handle.clone(),
+                    },
+                )),
+                _ => None,
+            },
+            TruncatedText::NodeStdout(i) => {
+                let output = self.nodes.get(i)?.output.as_ref()?;
+                Some((&output.stdout, output.stdout_truncated.clone()?))
+            }
+            TruncatedText::Stdout => match &self.state {
+                ExecutionState::Done(Ok(res)) => Some((&res.stdout, res.stdout_truncated.clone()?)),
+                _ => None,
+            },
         }
-    };
-    let request = Request::post("http://localhost:8080/execute")
-        .header("content-type", "application/json")
-        .body(Json(execution_params))
-        .unwrap();
-    web.fetch(request, handler.into())
+    }
+
+    fn truncated_mut(&mut self, text: TruncatedText) -> Option<(&mut String, Truncated)> {
+        match text {
+            TruncatedText::Node(i) => match &mut self.nodes.get_mut(i)?.output.as_mut()?.result {
+                NodeResult::Truncated {
+                    preview,
+                    full_len,
+                    handle,
+                    ..
+                } => Some((
+                    preview,
+                    Truncated {
+                        full_len: *full_len,
+                        handle: handle.clone(),
+                    },
+                )),
+                _ => None,
+            },
+            TruncatedText::NodeStdout(i) => {
+                let output = self.nodes.get_mut(i)?.output.as_mut()?;
+                let truncated = output.stdout_truncated.clone()?;
+                Some((&mut output.stdout, truncated))
+            }
+            TruncatedText::Stdout => match &mut self.state {
+                ExecutionState::Done(Ok(res)) => {
+                    let truncated = res.stdout_truncated.clone()?;
+                    Some((&mut res.stdout, truncated))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Persistent nodes only run in a session, which has a kernel of its own.
+    fn is_persistent(&self) -> bool {
+        self.persistent && self.session.is_some()
+    }
+
+    fn parameters(&self) -> ExecutionParameters {
+        ExecutionParameters {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| ExecutionNode {
+                    content: node.code.clone(),
+                })
+                .collect(),
+            persistent: self.is_persistent(),
+            // Persistent nodes are always built in the debug profile
+            build: if self.is_persistent() {
+                BuildOptions {
+                    edition: self.build.edition.clone(),
+                    ..BuildOptions::default()
+                }
+            } else {
+                self.build.clone()
+            },
+            session: self.session.clone(),
+            execution: None,
+        }
+    }
 }
 
 impl Renderable<Model> for Model {
@@ -306,19 +1154,40 @@ impl Renderable<Model> for Model {
             .nodes
             .iter()
             .enumerate()
-            .map(|(i, node)| node.view(move |value| Msg::ChangeNode(i, value), || Msg::Run));
+            .map(|(i, node)| {
+                node.view(
+                    move |value| Msg::ChangeNode(i, value),
+                    || Msg::Run,
+                    move |msg| Msg::Table(i, msg),
+                    move || Msg::ShowMore(TruncatedText::Node(i)),
+                    move || Msg::ShowMore(TruncatedText::NodeStdout(i)),
+                )
+            });
 
         let stats = match &self.state {
             ExecutionState::Done(Ok(res)) => {
-                html!{
-                    <div class="stats",>
+                let timing = &res.timing;
+                // Only the parts of the timing known for this run are shown
+                let stat = |name: &str, ns: Option<u64>| match ns {
+                    Some(ns) => html!{
                         <div class="stat",>
-                            <div>{"Build Time"}</div>
-                            <div>{format!("{}ms", res.build_time)}</div>
+                            <div>{name}</div>
+                            <div>{format_ns(ns as f64)}</div>
                         </div>
+                    },
+                    None => html!{{""}},
+                };
+                html!{
+                    <div class="stats",>
+                        {stat("Build Time", Some(timing.build_ns))}
+                        {stat("Compile", timing.compile_ns)}
+                        {stat("Codegen", timing.codegen_ns)}
+                        {stat("Link", timing.link_ns)}
+                        {stat("Run Time", Some(timing.run_ns))}
+                        {stat("CPU Time", timing.run_cpu_ns)}
                         <div class="stat",>
-                            <div>{"Run Time"}</div>
-                            <div>{format!("{}ms", res.run_time)}</div>
+                            <div>{"Profile"}</div>
+                            <div>{res.profile.to_string()}</div>
                         </div>
                     </div>
                 }
@@ -329,25 +1198,112 @@ impl Renderable<Model> for Model {
         html! {
             <div id="app", class=match self.state {
                 ExecutionState::Idle => "idle",
-                ExecutionState::Running(_) => "running",
+                ExecutionState::Running { .. } => "running",
                 ExecutionState::Done(Ok(_)) => "ok",
-                ExecutionState::Done(Err(_)) => "error",
+                ExecutionState::Done(Err(_))
+                | ExecutionState::Disconnected
+                | ExecutionState::BadEvent(_) => "error",
             },>
                 <div class="nodes",>{for nodes}</div>
                 <div class="controls",>
                     <button onclick=|_| Msg::Run,>{"Run"}</button>
+                    {if let ExecutionState::Running { execution: Some(_), .. } = self.state {
+                        html!{<button class="stop", onclick=|_| Msg::Stop,>{"Stop"}</button>}
+                    } else {
+                        html!{{""}}
+                    }}
                     <button onclick=|_| Msg::AddNode(String::new()),>{"Add Node"}</button>
+                    <label class="toggle",>
+                        <input
+                            type="checkbox",
+                            checked=self.is_persistent(),
+                            disabled=self.session.is_none(),
+                            title="Persistent nodes run in a session",
+                            onclick=|_| Msg::TogglePersistent,
+                        />
+                        {"Persistent"}
+                    </label>
+                    <select
+                        title="Profile",
+                        disabled=self.is_persistent(),
+                        onchange=|change| match change {
+                            ChangeData::Select(select) => match select.value().as_ref().map(String::as_str) {
+                                Some("release") => Msg::SetProfile(Profile::Release),
+                                _ => Msg::SetProfile(Profile::Debug),
+                            },
+                            _ => Msg::Noop,
+                        },
+                    >
+                        <option value="debug", selected=self.build.profile == Profile::Debug,>{"debug"}</option>
+                        <option value="release", selected=self.build.profile == Profile::Release,>{"release"}</option>
+                    </select>
+                    <select
+                        title="Edition",
+                        onchange=|change| match change {
+                            ChangeData::Select(select) => Msg::SetEdition(select.value()),
+                            _ => Msg::Noop,
+                        },
+                    >
+                        {for ["2015", "2018", "2021", "2024"].iter().map(|edition| html!{
+                            <option
+                                value=edition,
+                                selected=self.build.edition.as_ref().map_or("2015", String::as_str) == *edition,
+                            >{format!("Rust {}", edition)}</option>
+                        })}
+                    </select>
+                    <select
+                        title="Session",
+                        onchange=|change| match change {
+                            ChangeData::Select(select) => {
+                                Msg::SelectSession(select.value().filter(|id| id != ""))
+                            }
+                            _ => Msg::Noop,
+                        },
+                    >
+                        <option value="", selected=self.session.is_none(),>{"No session"}</option>
+                        {for self.sessions.iter().map(|session| html!{
+                            <option
+                                value=session.id.clone(),
+                                selected=self.session.as_ref() == Some(&session.id),
+                            >{session.name.clone()}</option>
+                        })}
+                    </select>
+                    <button onclick=|_| Msg::NewSession,>{"New Session"}</button>
+                    {if self.session.is_some() {
+                        html!{<button onclick=|_| Msg::DeleteSession,>{"Delete Session"}</button>}
+                    } else {
+                        html!{{""}}
+                    }}
                 </div>
                 <pre class="stdoutput",>
                     <code>{match &self.state {
                         ExecutionState::Idle => html!{{""}},
-                        ExecutionState::Running(_) => html!{{"Running..."}},
+                        ExecutionState::Running { status, output, .. } => if output == "" {
+                            html!{<span class="faded-text",>{
+                                if status == "" { "Running..." } else { status.as_str() }
+                            }</span>}
+                        } else {
+                            html!{{output}}
+                        },
                         ExecutionState::Done(Ok(res)) => if res.stdout == "" {
                             html!{<span class="faded-text",>{"none"}</span>}
+                        } else if let Some(truncated) = &res.stdout_truncated {
+                            html!{
+                                {&res.stdout}
+                                {show_more(
+                                    res.stdout.len(),
+                                    truncated.full_len,
+                                    || Msg::ShowMore(TruncatedText::Stdout),
+                                )}
+                            }
                         } else {
                             html!{{&res.stdout}}
                         },
                         ExecutionState::Done(Err(e)) => html!{{format!("{}", e)}},
+                        ExecutionState::Disconnected => html!{{"Lost the connection to the server."}},
+                        ExecutionState::BadEvent(error) => {
+                            html!{{format!("The server sent something unexpected: {}", error)}}
+                        }
                     }}</code>
                 </pre>
                 {stats}
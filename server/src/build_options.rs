@@ -0,0 +1,220 @@
+use shared::*;
+use std::process::Command;
+
+const OPT_LEVELS: &[&str] = &["0", "1", "2", "3", "s", "z"];
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+/// The codegen options which may be passed to rustc as `-C name=value`. Those
+/// reaching outside of the crate, like the linker, are left out.
+const CODEGEN_OPTIONS: &[&str] = &[
+    "codegen-units",
+    "debug-assertions",
+    "debuginfo",
+    "embed-bitcode",
+    "force-frame-pointers",
+    "lto",
+    "opt-level",
+    "overflow-checks",
+    "target-cpu",
+    "target-feature",
+];
+
+/// Checks the build options of an execution before they end up in the
+/// manifest and the environment of cargo.
+pub fn validate(options: &BuildOptions) -> Result<(), XXError> {
+    let invalid = |error: String| {
+        Err(XXError::BuildError {
+            error,
+            diagnostics: vec![],
+        })
+    };
+    if let Some(opt_level) = &options.opt_level {
+        if !OPT_LEVELS.contains(&opt_level.as_str()) {
+            return invalid(format!(
+                "Unknown opt-level `{}`, expected one of {}",
+                opt_level,
+                OPT_LEVELS.join(", ")
+            ));
+        }
+    }
+    if let Some(edition) = &options.edition {
+        if !EDITIONS.contains(&edition.as_str()) {
+            return invalid(format!(
+                "Unknown edition `{}`, expected one of {}",
+                edition,
+                EDITIONS.join(", ")
+            ));
+        }
+    }
+    if let Some(target_cpu) = &options.target_cpu {
+        let valid = !target_cpu.is_empty()
+            && target_cpu
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+        if !valid {
+            return invalid(format!("Invalid target-cpu `{}`", target_cpu));
+        }
+    }
+    if let Some(rustflags) = &options.rustflags {
+        let mut flags = rustflags.split_whitespace();
+        while let Some(flag) = flags.next() {
+            let option = match flag {
+                "-C" => flags.next().unwrap_or(""),
+                _ if flag.starts_with("-C") => &flag[2..],
+                _ => {
+                    return invalid(format!(
+                        "Unsupported flag for rustc `{}`, only `-C` options are allowed",
+                        flag
+                    ))
+                }
+            };
+            let name = option.split('=').next().unwrap_or("");
+            let valid = CODEGEN_OPTIONS.contains(&name)
+                && option
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.,+=".contains(c));
+            if !valid {
+                return invalid(format!(
+                    "Unsupported codegen option `{}`, expected one of {}",
+                    option,
+                    CODEGEN_OPTIONS.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Persistent nodes are built against the dylib the kernel was started with,
+/// in the `Debug` profile, so only their edition can be chosen.
+pub fn validate_persistent(options: &BuildOptions) -> Result<(), XXError> {
+    let chosen = [
+        ("profile", options.profile != Profile::Debug),
+        ("opt-level", options.opt_level.is_some()),
+        ("target-cpu", options.target_cpu.is_some()),
+        ("flags for rustc", options.rustflags.is_some()),
+    ];
+    match chosen.iter().find(|(_, chosen)| *chosen) {
+        Some((option, _)) => Err(XXError::BuildError {
+            error: format!(
+                "Persistent nodes are always built in the debug profile, so the {} cannot be chosen",
+                option
+            ),
+            diagnostics: vec![],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The edition for the `[package]` of a manifest.
+pub fn edition(options: &BuildOptions) -> &str {
+    options.edition.as_ref().map_or("2015", String::as_str)
+}
+
+/// Passes the opt-level and the flags for rustc to the cargo run by
+/// `command`. The profile is chosen when building.
+pub fn apply<'a>(command: &'a mut Command, options: &BuildOptions) -> &'a mut Command {
+    if let Some(opt_level) = &options.opt_level {
+        let var = match options.profile {
+            Profile::Debug => "CARGO_PROFILE_DEV_OPT_LEVEL",
+            Profile::Release => "CARGO_PROFILE_RELEASE_OPT_LEVEL",
+        };
+        command.env(var, opt_level);
+    }
+    let mut rustflags = vec![];
+    if let Some(target_cpu) = &options.target_cpu {
+        rustflags.push(format!("-C target-cpu={}", target_cpu));
+    }
+    rustflags.extend(options.rustflags.clone());
+    if !rustflags.is_empty() {
+        command.env("RUSTFLAGS", rustflags.join(" "));
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(rustflags: &str) -> BuildOptions {
+        BuildOptions {
+            rustflags: Some(rustflags.to_string()),
+            ..BuildOptions::default()
+        }
+    }
+
+    #[test]
+    fn valid_options_pass() {
+        let options = BuildOptions {
+            profile: Profile::Release,
+            opt_level: Some("s".to_string()),
+            target_cpu: Some("x86-64-v3".to_string()),
+            rustflags: Some(
+                "-C lto=fat -Ccodegen-units=1 -C target-feature=+avx2,-sse".to_string(),
+            ),
+            edition: Some("2021".to_string()),
+        };
+        assert!(validate(&options).is_ok());
+        assert!(validate(&BuildOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn invalid_options_are_build_errors() {
+        let invalid = [
+            BuildOptions {
+                opt_level: Some("4".to_string()),
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                edition: Some("2020".to_string()),
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                target_cpu: Some("native; rm -rf /".to_string()),
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                target_cpu: Some(String::new()),
+                ..BuildOptions::default()
+            },
+            flags("-L /tmp"),
+            flags("--extern foo=/tmp/libfoo.rlib"),
+            flags("-C linker=/tmp/evil"),
+            flags("-Clink-arg=-Wl,-rpath"),
+            flags("-C"),
+            flags("-C opt-level=3$(id)"),
+        ];
+        for options in &invalid {
+            match validate(options) {
+                Err(XXError::BuildError { diagnostics, .. }) => assert!(diagnostics.is_empty()),
+                result => panic!("{:?} for {:?}", result, options),
+            }
+        }
+    }
+
+    #[test]
+    fn persistent_nodes_only_choose_their_edition() {
+        let edition = BuildOptions {
+            edition: Some("2018".to_string()),
+            ..BuildOptions::default()
+        };
+        assert!(validate_persistent(&edition).is_ok());
+        let chosen = [
+            BuildOptions {
+                profile: Profile::Release,
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                opt_level: Some("1".to_string()),
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                target_cpu: Some("native".to_string()),
+                ..BuildOptions::default()
+            },
+            flags("-C lto"),
+        ];
+        for options in &chosen {
+            assert!(validate_persistent(options).is_err(), "{:?}", options);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// Stopping executions from `/cancel/{execution}`.
+//
+// Every execution gets an id, sent to the client in the `Started` event of
+// `/stream`, and a `Cancel` which the processes run on its behalf keep
+// checking. Cancelling it kills whichever of them is running. As `POST
+// /execute` only responds once the execution is done, the client can choose
+// the id up front instead.
+
+use actix_web::{HttpRequest, Json, Path};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ids::{is_id, new_id};
+use shared::*;
+use {lock, AppState};
+
+/// How often whatever an execution waits for checks whether it was cancelled.
+pub const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// Shared between an execution and whoever might cancel it.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The executions which can be cancelled, by their ids.
+#[derive(Default)]
+pub struct Running {
+    executions: Mutex<HashMap<String, Cancel>>,
+}
+
+impl Running {
+    /// Makes `cancel` reachable through the returned id, until `finish`.
+    /// The id is `chosen` by the client, or made up when it is `None`.
+    pub fn start(&self, cancel: &Cancel, chosen: Option<&str>) -> Result<String, XXError> {
+        let mut executions = lock(&self.executions);
+        let id = match chosen {
+            Some(id) if is_id(id) && !executions.contains_key(id) => id.to_string(),
+            Some(id) => {
+                return Err(XXError::InvalidExecutionId {
+                    execution: id.to_string(),
+                })
+            }
+            None => new_id().expect("failed to create execution id"),
+        };
+        executions.insert(id.clone(), cancel.clone());
+        Ok(id)
+    }
+
+    pub fn finish(&self, id: &str) {
+        lock(&self.executions).remove(id);
+    }
+
+    fn cancel(&self, id: &str) -> bool {
+        match lock(&self.executions).get(id) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// `POST /cancel/{execution}`, which is `false` when the execution is not
+/// running (any more).
+pub fn cancel((req, id): (HttpRequest<AppState>, Path<String>)) -> Json<bool> {
+    Json(req.state().running.cancel(&id.into_inner()))
+}
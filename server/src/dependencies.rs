@@ -0,0 +1,389 @@
+use shared::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use cancel::Cancel;
+use process;
+use source_map;
+use ParsedNode;
+
+/// Crates which come with the compiler and never need to be declared.
+const BUILTIN_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro", "test", "self"];
+
+/// The `[dependencies]` of the generated crates, declared by the nodes either
+/// with a `// dep: regex = "1"` directive or a plain `extern crate regex;`.
+/// Keyed by the normalized name, with the name as it was written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dependencies(BTreeMap<String, (String, Requirement)>);
+
+/// What a directive may ask of a dependency. Everything comes from
+/// crates.io, so nodes can't make the server build from arbitrary git
+/// repositories or paths.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Requirement {
+    version: String,
+    features: Vec<String>,
+    default_features: Option<bool>,
+}
+
+impl Requirement {
+    fn version(version: &str) -> Requirement {
+        Requirement {
+            version: version.to_string(),
+            ..Requirement::default()
+        }
+    }
+
+    fn to_toml(&self) -> String {
+        if self.features.is_empty() && self.default_features.is_none() {
+            return format!("{:?}", self.version);
+        }
+        let mut toml = format!("{{ version = {:?}", self.version);
+        if !self.features.is_empty() {
+            let features = self
+                .features
+                .iter()
+                .map(|feature| format!("{:?}", feature))
+                .collect::<Vec<_>>();
+            toml.push_str(&format!(", features = [{}]", features.join(", ")));
+        }
+        if let Some(default_features) = self.default_features {
+            toml.push_str(&format!(", default-features = {}", default_features));
+        }
+        toml.push_str(" }");
+        toml
+    }
+}
+
+impl Dependencies {
+    pub fn collect(nodes: &[ParsedNode]) -> Result<Dependencies, XXError> {
+        let mut dependencies = Dependencies::default();
+        for (i, node) in nodes.iter().enumerate() {
+            for range in source_map::line_comments(&node.source) {
+                let comment = &node.source[range];
+                let directive = comment["//".len()..].trim();
+                if !directive.starts_with("dep:") {
+                    continue;
+                }
+                let (name, requirement) =
+                    parse_directive(&directive["dep:".len()..]).ok_or_else(|| {
+                        XXError::DependencyError {
+                            error: format!(
+                                "Invalid directive in node {}:\n  {}\nExpected something like `// dep: regex = \"1\"` or `// dep: serde = {{ version = \"1\", features = [\"derive\"] }}`",
+                                i, comment
+                            ),
+                        }
+                    })?;
+                dependencies.insert(name, requirement);
+            }
+            for stmt in &node.stmts {
+                if let syn::Stmt::Item(syn::Item::ExternCrate(item)) = stmt {
+                    let name = item.ident.to_string();
+                    if !BUILTIN_CRATES.contains(&&*name) && !dependencies.contains(&name) {
+                        dependencies.insert(name, Requirement::version("*"));
+                    }
+                }
+            }
+        }
+        // Values are rendered as tables using `serde_json`, when the nodes
+        // use `serde`
+        if dependencies.contains("serde") && !dependencies.contains("serde_json") {
+            dependencies.insert("serde_json".to_string(), Requirement::version("1"));
+        }
+        Ok(dependencies)
+    }
+
+    /// Whether the generated crates can render values as tables.
+    pub fn has_tables(&self) -> bool {
+        self.contains("serde") && self.contains("serde_json")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(&normalize(name))
+    }
+
+    /// Later declarations replace earlier ones, however they spell the name.
+    fn insert(&mut self, name: String, requirement: Requirement) {
+        self.0.insert(normalize(&name), (name, requirement));
+    }
+
+    pub fn to_toml(&self) -> String {
+        self.0
+            .values()
+            .map(|(name, requirement)| format!("{} = {}\n", name, requirement.to_toml()))
+            .collect()
+    }
+
+    /// Resolves and downloads the dependencies of the crate in `dir`, so
+    /// failing to do so can be told apart from a failing build.
+    pub fn fetch(
+        &self,
+        dir: &Path,
+        timeout: Duration,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<(), XXError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let output = process::output(
+            Command::new("cargo").current_dir(dir).arg("fetch"),
+            timeout,
+            cancel,
+            &mut |_, line| events(ExecutionEvent::Build(line.to_string())),
+        ).map_err(|overrun| overrun.into_error(Phase::Build))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let error = String::from_utf8_lossy(&output.stderr).into_owned();
+            Err(XXError::DependencyError { error })
+        }
+    }
+}
+
+/// The contents of `.cargo/config.toml` for the build directory, resolving
+/// dependencies from a vendor directory and/or without network access.
+pub fn cargo_config(vendor_dir: Option<&PathBuf>, offline: bool) -> String {
+    let mut config = String::new();
+    if offline {
+        config.push_str("[net]\noffline = true\n\n");
+    }
+    if let Some(vendor_dir) = vendor_dir {
+        config.push_str(&format!(
+            r#"[source.crates-io]
+replace-with = "vendored-sources"
+
+[source.vendored-sources]
+directory = {:?}
+"#,
+            vendor_dir.display().to_string()
+        ));
+    }
+    config
+}
+
+/// Parses the `name = requirement` of a directive, where the requirement is
+/// either a version, or a table with a `version` and optionally `features`
+/// and `default-features`.
+fn parse_directive(dependency: &str) -> Option<(String, Requirement)> {
+    let mut parts = dependency.splitn(2, '=');
+    let name = parts.next()?.trim();
+    if !is_name(name) {
+        return None;
+    }
+    let mut toml = Toml(parts.next()?.trim());
+    let requirement = if toml.eat("{") {
+        let mut requirement = None;
+        let mut features = None;
+        let mut default_features = None;
+        while !toml.eat("}") {
+            let key = toml.key()?;
+            if !toml.eat("=") {
+                return None;
+            }
+            match key {
+                "version" if requirement.is_none() => requirement = Some(toml.string()?),
+                "features" if features.is_none() => features = Some(toml.strings()?),
+                "default-features" if default_features.is_none() => {
+                    default_features = Some(toml.boolean()?)
+                }
+                _ => return None,
+            }
+            if !toml.eat(",") && !toml.0.starts_with('}') {
+                return None;
+            }
+        }
+        Requirement {
+            version: requirement?,
+            features: features.unwrap_or_default(),
+            default_features,
+        }
+    } else {
+        Requirement::version(&toml.string()?)
+    };
+    let valid_version = requirement
+        .version
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || " .*^~<>=,-+".contains(c));
+    let valid_features = requirement.features.iter().all(|feature| {
+        !feature.is_empty()
+            && feature
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-/+.".contains(c))
+    });
+    if toml.0.is_empty() && valid_version && valid_features {
+        Some((name.to_string(), requirement))
+    } else {
+        None
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The little of TOML a requirement is written in, with what is left of it.
+struct Toml<'a>(&'a str);
+
+impl<'a> Toml<'a> {
+    fn eat(&mut self, token: &str) -> bool {
+        if self.0.starts_with(token) {
+            self.0 = self.0[token.len()..].trim();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn key(&mut self) -> Option<&'a str> {
+        let end = self
+            .0
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.0.len());
+        let key = &self.0[..end];
+        self.0 = self.0[end..].trim();
+        if key.is_empty() {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
+    /// A basic string without escapes, which a version or feature never needs.
+    fn string(&mut self) -> Option<String> {
+        if !self.0.starts_with('"') {
+            return None;
+        }
+        let end = self.0[1..].find(['"', '\\'])? + 1;
+        if &self.0[end..end + 1] != "\"" {
+            return None;
+        }
+        let string = self.0[1..end].to_string();
+        self.0 = self.0[end + 1..].trim();
+        Some(string)
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        if !self.eat("[") {
+            return None;
+        }
+        let mut strings = vec![];
+        while !self.eat("]") {
+            strings.push(self.string()?);
+            if !self.eat(",") && !self.0.starts_with(']') {
+                return None;
+            }
+        }
+        Some(strings)
+    }
+
+    fn boolean(&mut self) -> Option<bool> {
+        if self.eat("true") {
+            Some(true)
+        } else if self.eat("false") {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Crates are referred to with underscores, while packages may use dashes.
+fn normalize(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives_take_a_version_or_a_table() {
+        assert_eq!(
+            parse_directive(r#" regex = "1" "#),
+            Some(("regex".to_string(), Requirement::version("1")))
+        );
+        assert_eq!(
+            parse_directive(
+                r#"serde = { version = "1.0", features = ["derive", "rc"], default-features = false }"#
+            ),
+            Some((
+                "serde".to_string(),
+                Requirement {
+                    version: "1.0".to_string(),
+                    features: vec!["derive".to_string(), "rc".to_string()],
+                    default_features: Some(false),
+                }
+            ))
+        );
+        // Trailing commas are fine, as in TOML
+        assert!(
+            parse_directive(r#"rand = { version = "0.8", features = ["small_rng",], }"#).is_some()
+        );
+    }
+
+    #[test]
+    fn directives_only_ask_crates_io_for_valid_requirements() {
+        for directive in &[
+            r#"regex"#,
+            r#"= "1""#,
+            r#"re gex = "1""#,
+            r#"regex = 1"#,
+            r#"regex = "1" trailing"#,
+            r#"regex = "1\"""#,
+            r#"regex = "1; rm -rf /""#,
+            r#"regex = { git = "https://example.com/regex" }"#,
+            r#"regex = { path = "/etc" }"#,
+            r#"regex = { features = ["unicode"] }"#,
+            r#"regex = { version = "1", version = "2" }"#,
+            r#"regex = { version = "1" "#,
+            r#"regex = { version = "1" features = [] }"#,
+            r#"regex = { version = "1", features = [""] }"#,
+            r#"regex = { version = "1", features = ["a b"] }"#,
+            r#"regex = { version = "1", default-features = "no" }"#,
+        ] {
+            assert_eq!(parse_directive(directive), None, "{}", directive);
+        }
+    }
+
+    #[test]
+    fn toml_strings_stop_at_escapes() {
+        let mut toml = Toml(r#""1.0" , rest"#);
+        assert_eq!(toml.string(), Some("1.0".to_string()));
+        assert!(toml.eat(","));
+        assert_eq!(toml.0, "rest");
+        assert_eq!(Toml(r#""a\"b""#).string(), None);
+        assert_eq!(Toml(r#""unterminated"#).string(), None);
+        assert_eq!(Toml("bare").string(), None);
+        assert_eq!(
+            Toml(r#"["a", "é"]"#).strings(),
+            Some(vec!["a".to_string(), "é".to_string()])
+        );
+        assert_eq!(Toml("[1]").strings(), None);
+        assert_eq!(
+            Toml("default-features = true").key(),
+            Some("default-features")
+        );
+        assert_eq!(Toml("= true").key(), None);
+        assert_eq!(Toml("false").boolean(), Some(false));
+        assert_eq!(Toml("yes").boolean(), None);
+    }
+
+    #[test]
+    fn later_declarations_replace_earlier_spellings() {
+        let mut dependencies = Dependencies::default();
+        dependencies.insert("serde-json".to_string(), Requirement::version("1"));
+        dependencies.insert("serde_json".to_string(), Requirement::version("1.0.100"));
+        assert!(dependencies.contains("serde-json"));
+        assert_eq!(dependencies.to_toml(), "serde_json = \"1.0.100\"\n");
+    }
+}
@@ -0,0 +1,150 @@
+use shared::*;
+use std::process::Command;
+use std::time::Duration;
+
+use cancel::Cancel;
+use process::{self, Pipe};
+use source_map::SourceMap;
+use timings;
+
+/// A line of `cargo build --message-format=json`.
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<CompilerMessage>,
+}
+
+#[derive(Deserialize)]
+struct CompilerMessage {
+    message: String,
+    code: Option<CompilerCode>,
+    level: String,
+    spans: Vec<CompilerSpan>,
+    children: Vec<CompilerMessage>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompilerCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct CompilerSpan {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    suggested_replacement: Option<String>,
+    expansion: Option<Box<CompilerExpansion>>,
+}
+
+#[derive(Deserialize)]
+struct CompilerExpansion {
+    span: CompilerSpan,
+}
+
+/// Runs `cargo build` for `command`, with `profile`. A failing build is
+/// reported as a `BuildError`, with the diagnostics pointing into `file`
+/// mapped back to the nodes through `map`. The timings of the build are left
+/// for `timings::read`.
+pub fn build(
+    command: &mut Command,
+    profile: Profile,
+    timeout: Duration,
+    cancel: &Cancel,
+    file: &str,
+    map: &SourceMap,
+    events: &dyn Fn(ExecutionEvent),
+) -> Result<(), XXError> {
+    let mut rendered = String::new();
+    let mut diagnostics = vec![];
+    timings::apply(command.arg("build").arg("--message-format=json"));
+    if profile == Profile::Release {
+        command.arg("--release");
+    }
+    let output = process::output(
+        command,
+        timeout,
+        cancel,
+        &mut |pipe, line| match pipe {
+            Pipe::Stderr => events(ExecutionEvent::Build(line.to_string())),
+            Pipe::Stdout => {
+                let message = match serde_json::from_str::<CargoMessage>(line) {
+                    Ok(CargoMessage {
+                        ref reason,
+                        message: Some(message),
+                    }) if reason == "compiler-message" => message,
+                    _ => return,
+                };
+                if let Some(text) = &message.rendered {
+                    for line in text.lines() {
+                        events(ExecutionEvent::Build(line.to_string()));
+                    }
+                    rendered.push_str(text);
+                }
+                diagnostics.push(diagnostic(message, file, map));
+            }
+        },
+    ).map_err(|overrun| overrun.into_error(Phase::Build))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let error = rendered + &String::from_utf8_lossy(&output.stderr);
+        Err(XXError::BuildError { error, diagnostics })
+    }
+}
+
+fn diagnostic(message: CompilerMessage, file: &str, map: &SourceMap) -> Diagnostic {
+    let location = message
+        .spans
+        .iter()
+        .filter(|span| span.is_primary)
+        .chain(&message.spans)
+        .filter_map(|span| lookup(span, file, map))
+        .next();
+    let suggestions = message
+        .children
+        .iter()
+        .flat_map(|child| child.spans.iter().map(move |span| (child, span)))
+        .filter_map(|(child, span)| {
+            let replacement = span.suggested_replacement.clone()?;
+            let (_, span) = lookup(span, file, map)?;
+            Some(Suggestion {
+                message: child.message.clone(),
+                span,
+                replacement,
+            })
+        })
+        .collect();
+    Diagnostic {
+        level: message.level,
+        message: message.message,
+        code: message.code.map(|code| code.code),
+        node: location.map(|(node, _)| node),
+        span: location.map(|(_, span)| span),
+        suggestions,
+    }
+}
+
+/// Maps a span of the generated source back to a node. Spans inside of macros
+/// are mapped through the place the macro was invoked.
+fn lookup(span: &CompilerSpan, file: &str, map: &SourceMap) -> Option<(usize, Span)> {
+    if span.file_name != file {
+        let expansion = span.expansion.as_ref()?;
+        return lookup(&expansion.span, file, map);
+    }
+    map.lookup_span(
+        LineColumn {
+            line: span.line_start,
+            column: span.column_start,
+        },
+        LineColumn {
+            line: span.line_end,
+            column: span.column_end,
+        },
+    )
+}
@@ -0,0 +1,119 @@
+use shared::*;
+
+use source_map;
+
+/// The `//: ...` directives of a node, changing how it is run. Each directive
+/// is a word on a comment line of its own, like `//: display`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Directives {
+    /// How the result of the node is formatted
+    pub format: Format,
+    /// Where the node asks for its value to be benchmarked, instead of
+    /// evaluated once
+    pub bench: Option<Span>,
+}
+
+impl Default for Directives {
+    fn default() -> Directives {
+        Directives {
+            format: Format::Debug,
+            bench: None,
+        }
+    }
+}
+
+impl Directives {
+    pub fn parse(node: usize, content: &str) -> Result<Directives, XXError> {
+        let mut directives = Directives::default();
+        for range in source_map::line_comments(content) {
+            // A comment after some code is no directive
+            let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+            if !content[line_start..range.start].trim().is_empty() {
+                continue;
+            }
+            let comment = content[range.clone()].trim_end();
+            let span = Span {
+                start: source_map::position(content, range.start),
+                end: source_map::position(content, range.start + comment.len()),
+            };
+            let words = match comment.strip_prefix("//:") {
+                Some(words) => words,
+                None => continue,
+            };
+            for word in words.split_whitespace() {
+                match word {
+                    "debug" => directives.format = Format::Debug,
+                    "pretty" => directives.format = Format::PrettyDebug,
+                    "display" => directives.format = Format::Display,
+                    "bench" => directives.bench = Some(span),
+                    _ => {
+                        return Err(XXError::ParseNode {
+                            src: content.to_string(),
+                            error: format!(
+                                "Unknown directive `{}`, expected one of `debug`, `pretty`, `display` or `bench`",
+                                word
+                            ),
+                            node,
+                            span,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(directives)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> LineColumn {
+        LineColumn { line, column }
+    }
+
+    #[test]
+    fn directives_are_words_on_comment_lines() {
+        let directives =
+            Directives::parse(0, "//: display\nlet x = 1;\n  //: pretty bench \nx").unwrap();
+        assert_eq!(directives.format, Format::PrettyDebug);
+        let bench = directives.bench.unwrap();
+        assert_eq!(bench.start, at(3, 3));
+        assert_eq!(bench.end, at(3, 19));
+        assert_eq!(
+            Directives::parse(0, "1 + 1").unwrap(),
+            Directives::default()
+        );
+    }
+
+    #[test]
+    fn only_comments_of_their_own_are_directives() {
+        for content in &[
+            "let s = \"\n//: bench\n\";",
+            "/*\n//: bench\n*/",
+            "let x = 1; //: bench",
+            "// : bench",
+        ] {
+            assert_eq!(
+                Directives::parse(0, content).unwrap(),
+                Directives::default(),
+                "{:?}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_directives_are_errors() {
+        match Directives::parse(3, "1\n//: benhc") {
+            Err(XXError::ParseNode {
+                error, node, span, ..
+            }) => {
+                assert!(error.contains("`benhc`"));
+                assert_eq!(node, 3);
+                assert_eq!(span.start, at(2, 1));
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
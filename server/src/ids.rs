@@ -0,0 +1,17 @@
+// The ids of sessions and executions, which are all it takes to use a
+// session or to cancel an execution.
+
+use std::io::Read;
+
+/// A random id, which is hard to guess.
+pub fn new_id() -> Result<String, failure::Error> {
+    let mut bytes = [0; 8];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Ids name directories and packages, so they are kept to lowercase hex
+/// digits.
+pub fn is_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| "0123456789abcdef".contains(c))
+}
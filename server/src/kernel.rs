@@ -0,0 +1,979 @@
+// Persistent execution of nodes.
+//
+// Every evaluated node is compiled into its own dylib and loaded into a long
+// lived host process. Top-level `let` bindings are moved into a store owned by
+// the host when a node finishes, and moved back out by later nodes mentioning
+// them. This way only new or edited nodes have to be evaluated on a run.
+//
+// The items of a node are made public, and the dylibs of later nodes link to
+// it and import them, so they are the very same items in every node.
+
+use shared::*;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use build_options;
+use cancel::{Cancel, CANCEL_POLL};
+use dependencies::Dependencies;
+use diagnostics;
+use outputs::Outputs;
+use process::{self, millis, nanos};
+use sandbox::Sandbox;
+use source_map::{SourceBuilder, SourceMap};
+use timings::{self, CrateTimings};
+use {
+    lock, map_type_paths, prepare_attributes, prepare_items, prepare_node, Config, NodeStdout,
+    NodeValue, ParsedNode, PRELUDE, TABLE_PRELUDE,
+};
+
+const HOST_SRC: &str = r#"
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic;
+
+#[cfg_attr(target_os = "linux", link(name = "dl"))]
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+}
+
+const RTLD_NOW: c_int = 2;
+
+type Node = fn(&mut HashMap<String, Box<dyn Any>>) -> String;
+
+unsafe fn load(path: &str) -> Result<Node, String> {
+    let path = CString::new(path).map_err(|e| e.to_string())?;
+    let handle = dlopen(path.as_ptr(), RTLD_NOW);
+    if handle.is_null() {
+        return Err(CStr::from_ptr(dlerror()).to_string_lossy().into_owned());
+    }
+    let symbol = dlsym(handle, b"__repl_node\0".as_ptr() as *const c_char);
+    if symbol.is_null() {
+        return Err(CStr::from_ptr(dlerror()).to_string_lossy().into_owned());
+    }
+    Ok(std::mem::transmute::<*mut c_void, Node>(symbol))
+}
+
+fn main() {
+    let nonce = std::env::args().nth(1).expect("missing nonce");
+    let mut store: HashMap<String, Box<dyn Any>> = HashMap::new();
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let path = line.expect("failed to read command");
+        let outcome = match unsafe { load(&path) } {
+            Ok(node) => panic::catch_unwind(panic::AssertUnwindSafe(|| node(&mut store)))
+                .map_err(|payload| {
+                    payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "node panicked".to_string())
+                }),
+            Err(error) => Err(error),
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        match outcome {
+            Ok(output) => writeln!(stdout, "\n{} ok {}", nonce, output),
+            Err(error) => writeln!(stdout, "\n{} err {:?}", nonce, error),
+        }.and_then(|_| stdout.flush())
+            .expect("failed to write result");
+    }
+}
+"#;
+
+#[cfg(target_os = "macos")]
+const LIBRARY_PATH_VAR: &str = "DYLD_LIBRARY_PATH";
+#[cfg(not(target_os = "macos"))]
+const LIBRARY_PATH_VAR: &str = "LD_LIBRARY_PATH";
+
+#[derive(Debug, Deserialize)]
+struct NodeReturn {
+    result: Option<NodeValue>,
+    bindings: Vec<(String, String)>,
+    time_ns: u64,
+}
+
+pub struct Kernel {
+    dir: PathBuf,
+    build_timeout: Duration,
+    run_timeout: Duration,
+    sandbox: Sandbox,
+    _scratch_dir: tempfile::TempDir,
+    process: Child,
+    stdin: ChildStdin,
+    stdout: Receiver<String>,
+    stderr: Arc<Mutex<String>>,
+    stderr_reader: Option<JoinHandle<()>>,
+    nonce: String,
+    generation: usize,
+    dead: bool,
+    bindings: BTreeMap<String, String>,
+    evaluated: Vec<Evaluated>,
+    /// The crates of every node compiled so far, which the types of bindings
+    /// might be from
+    libraries: Vec<String>,
+    dependencies: Dependencies,
+    edition: String,
+}
+
+struct Evaluated {
+    content: String,
+    output: NodeOutput,
+    declared: Vec<String>,
+    /// The crate of the node, and the names of the items it defines
+    library: String,
+    items: Vec<String>,
+    stale: bool,
+}
+
+struct Compiled {
+    library: String,
+    lib: PathBuf,
+    restored: Vec<String>,
+    declared: Vec<String>,
+    /// Of all the builds it took
+    timings: CrateTimings,
+}
+
+impl Kernel {
+    pub fn start(
+        build_dir: &Path,
+        run_dir: Option<&Path>,
+        config: &Config,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<Kernel, XXError> {
+        let dir = build_dir.join("kernel");
+        let host_dir = dir.join("host");
+        std::fs::create_dir_all(host_dir.join("src")).expect("failed to create kernel dir");
+        std::fs::write(
+            host_dir.join("Cargo.toml"),
+            r#"[package]
+name = "host"
+version = "0.1.0"
+
+[workspace]
+"#,
+        ).expect("failed to write file");
+        std::fs::write(host_dir.join("src/main.rs"), HOST_SRC).expect("failed to write file");
+        build(
+            &dir,
+            &host_dir,
+            "src/main.rs",
+            &SourceMap::default(),
+            config.build_timeout,
+            cancel,
+            events,
+        )?;
+
+        let sysroot_libs = std::process::Command::new("rustc")
+            .args(["--print", "target-libdir"])
+            .output()
+            .expect("failed to locate the standard library");
+        let library_path = format!(
+            "{}:{}",
+            String::from_utf8_lossy(&sysroot_libs.stdout).trim(),
+            dir.join("target/debug/deps").display()
+        );
+
+        let scratch_dir = tempfile::tempdir().expect("failed to create scratch dir");
+        let run_dir = run_dir.unwrap_or_else(|| scratch_dir.path());
+        let sandbox = config.sandbox.without_cpu_limit();
+
+        let nonce = format!("__repl_{}", time::precise_time_ns());
+        // The kernel runs in a process group of its own, so nothing a node
+        // started outlives it
+        let mut command = Command::new(dir.join("target/debug/host"));
+        let mut process = sandbox
+            .apply(process::own_process_group(&mut command), run_dir)
+            .arg(&nonce)
+            .env(LIBRARY_PATH_VAR, &library_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start kernel");
+
+        let stdin = process.stdin.take().unwrap();
+        let stdout = {
+            let (sender, receiver) = mpsc::channel();
+            let child_stdout = BufReader::new(process.stdout.take().unwrap());
+            std::thread::spawn(move || {
+                for line in child_stdout.split(b'\n') {
+                    let mut line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    line.push(b'\n');
+                    if sender.send(String::from_utf8_lossy(&line).into_owned()).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        };
+        let stderr = Arc::new(Mutex::new(String::new()));
+        let stderr_reader = {
+            let stderr = stderr.clone();
+            let mut child_stderr = process.stderr.take().unwrap();
+            std::thread::spawn(move || {
+                let mut buf = [0; 1024];
+                while let Ok(n) = child_stderr.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    stderr
+                        .lock()
+                        .unwrap()
+                        .push_str(&String::from_utf8_lossy(&buf[..n]));
+                }
+            })
+        };
+
+        Ok(Kernel {
+            dir,
+            build_timeout: config.build_timeout,
+            run_timeout: config.run_timeout,
+            sandbox,
+            _scratch_dir: scratch_dir,
+            process,
+            stdin,
+            stdout,
+            stderr,
+            stderr_reader: Some(stderr_reader),
+            nonce,
+            generation: 0,
+            dead: false,
+            bindings: BTreeMap::new(),
+            evaluated: vec![],
+            libraries: vec![],
+            dependencies: Dependencies::default(),
+            edition: "2015".to_string(),
+        })
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    pub fn run(
+        &mut self,
+        nodes: Vec<(String, ParsedNode)>,
+        dependencies: Dependencies,
+        options: &BuildOptions,
+        outputs: &Mutex<Outputs>,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<ExecutionResult, XXError> {
+        self.dependencies = dependencies;
+        // Nodes depend on the dylib of the host, so only the edition can change
+        // between runs, which has all of the nodes compiled again.
+        let edition = build_options::edition(options).to_string();
+        if edition != self.edition {
+            self.edition = edition;
+            for evaluated in &mut self.evaluated {
+                evaluated.stale = true;
+            }
+        }
+        let stdout_limit = lock(outputs).stdout_limit();
+
+        let mut build_time = Duration::from_secs(0);
+        let mut run_time = Duration::from_secs(0);
+        let mut compile_timings = CrateTimings::default();
+        let mut stdout = String::new();
+
+        self.evaluated.truncate(nodes.len());
+
+        for (i, (content, parsed)) in nodes.into_iter().enumerate() {
+            let unchanged = self
+                .evaluated
+                .get(i)
+                .is_some_and(|evaluated| !evaluated.stale && evaluated.content == content);
+            if unchanged {
+                continue;
+            }
+
+            let start_time = Instant::now();
+            let compiled = self.compile(i, &parsed, cancel, events)?;
+            let compiled_time = Instant::now();
+            let printed = stdout.len();
+            let format = parsed.directives.format;
+            let output = self.eval(
+                &compiled,
+                format,
+                &mut stdout,
+                stdout_limit,
+                cancel,
+                events,
+            );
+            let evaluated_time = Instant::now();
+
+            build_time += compiled_time - start_time;
+            run_time += evaluated_time - compiled_time;
+            compile_timings = compile_timings.add(compiled.timings);
+
+            // Bindings which the node consumed are gone, so the nodes declaring
+            // them have to be evaluated again on the next run.
+            for name in &compiled.restored {
+                if self.bindings.contains_key(name) {
+                    continue;
+                }
+                for evaluated in &mut self.evaluated {
+                    if evaluated.declared.contains(name) {
+                        evaluated.stale = true;
+                    }
+                }
+            }
+
+            let output = {
+                let (result, time_ns) = output?;
+                let mut outputs = lock(outputs);
+                let (stdout, stdout_truncated) = outputs.node_stdout(&stdout[printed..], printed);
+                NodeOutput {
+                    result: outputs.result(result),
+                    stdout,
+                    stdout_truncated,
+                    time_ns: Some(time_ns),
+                }
+            };
+            events(ExecutionEvent::Node {
+                node: i,
+                output: output.clone(),
+            });
+            let evaluated = Evaluated {
+                content,
+                output,
+                declared: compiled.declared,
+                library: compiled.library,
+                items: item_names(&parsed.stmts),
+                stale: false,
+            };
+            if i < self.evaluated.len() {
+                self.evaluated[i] = evaluated;
+            } else {
+                self.evaluated.push(evaluated);
+            }
+        }
+
+        let (stdout, stdout_truncated) = lock(outputs).stdout(stdout);
+        Ok(ExecutionResult {
+            build_time: millis(build_time),
+            run_time: millis(run_time),
+            timing: Timing {
+                build_ns: nanos(build_time),
+                compile_ns: compile_timings.compile.map(nanos),
+                codegen_ns: compile_timings.codegen.map(nanos),
+                link_ns: compile_timings.link.map(nanos),
+                run_ns: nanos(run_time),
+                run_cpu_ns: None,
+            },
+            profile: options.profile,
+            nodes: self
+                .evaluated
+                .iter()
+                .map(|evaluated| evaluated.output.clone())
+                .collect(),
+            stdout,
+            stdout_truncated,
+            queue_position: None,
+        })
+    }
+
+    fn compile(
+        &mut self,
+        node: usize,
+        parsed: &ParsedNode,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<Compiled, XXError> {
+        self.generation += 1;
+        let name = format!("node_{}", self.generation);
+        let crate_dir = self.dir.join("nodes").join(&name);
+        std::fs::create_dir_all(&crate_dir).expect("failed to create node dir");
+        std::fs::write(
+            crate_dir.join("Cargo.toml"),
+            format!(
+                r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "{}"
+
+[lib]
+crate-type = ["dylib"]
+path = "lib.rs"
+
+[workspace]
+
+[dependencies]
+{}"#,
+                name,
+                self.edition,
+                self.dependencies.to_toml()
+            ),
+        ).expect("failed to write file");
+
+        // Every node is its own crate, so the lock file is passed along to
+        // keep the versions of dependencies from changing between nodes.
+        let lock_file = self.dir.join("Cargo.lock");
+        if lock_file.exists() {
+            std::fs::copy(&lock_file, crate_dir.join("Cargo.lock")).expect("failed to copy lock file");
+        }
+        self.dependencies
+            .fetch(&crate_dir, self.build_timeout, cancel, events)?;
+
+        // Every earlier crate is linked, as bindings might be of their types,
+        // and the items of the nodes before this one are imported, the last
+        // one defining a name winning. The node's own items come first.
+        let mut header = self
+            .libraries
+            .iter()
+            .map(|library| format!("extern crate {};\n", library))
+            .collect::<String>();
+        let own_items = item_names(&parsed.stmts);
+        let mut imports = BTreeMap::new();
+        for evaluated in &self.evaluated[..node.min(self.evaluated.len())] {
+            for item in &evaluated.items {
+                if !own_items.contains(item) {
+                    imports.insert(item.clone(), evaluated.library.clone());
+                }
+            }
+        }
+        for (item, library) in &imports {
+            header.push_str(&format!("#[allow(unused_imports)]\nuse {}::{};\n", library, item));
+        }
+
+        let mentioned = mentioned_idents(&parsed.stmts);
+        let restored = self
+            .bindings
+            .iter()
+            .filter(|(name, _)| mentioned.contains(*name))
+            .map(|(name, ty)| (name.clone(), ty.clone()))
+            .collect::<Vec<_>>();
+        let declared = declared_idents(&parsed.stmts);
+        let mut persisted = restored
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in &declared {
+            if !persisted.contains(name) {
+                persisted.push(name.clone());
+            }
+        }
+
+        // Bindings which are moved or borrowed by the node cannot be put back
+        // into the store, so drop those named by the compiler and try again.
+        let mut timings = CrateTimings::default();
+        loop {
+            let (src, source_map) = node_src(
+                &header,
+                &restored,
+                node,
+                parsed,
+                &persisted,
+                self.dependencies.has_tables(),
+                &self.edition,
+            );
+            std::fs::write(crate_dir.join("lib.rs"), src).expect("failed to write file");
+            let built = build(
+                &self.dir,
+                &crate_dir,
+                "lib.rs",
+                &source_map,
+                self.build_timeout,
+                cancel,
+                events,
+            );
+            timings = timings.add(timings::read(&self.dir.join("target"), &name));
+            match built {
+                Ok(()) => break,
+                Err(XXError::BuildError { error, diagnostics }) => {
+                    let failed = names_in_errors(&error);
+                    let before = persisted.len();
+                    persisted.retain(|name| !failed.contains(name));
+                    if persisted.len() == before {
+                        return Err(XXError::BuildError { error, diagnostics });
+                    }
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        std::fs::copy(crate_dir.join("Cargo.lock"), &lock_file).expect("failed to copy lock file");
+        self.libraries.push(name.clone());
+
+        // Loaded from where later nodes linking to it find it, so it is only
+        // loaded once
+        let lib = self.dir.join("target/debug/deps").join(format!(
+            "{}{}{}",
+            std::env::consts::DLL_PREFIX,
+            name,
+            std::env::consts::DLL_SUFFIX
+        ));
+        Ok(Compiled {
+            library: name,
+            lib,
+            restored: restored.into_iter().map(|(name, _)| name).collect(),
+            declared,
+            timings,
+        })
+    }
+
+    /// Evaluates a node, appending what it prints to `stdout`. Only
+    /// as much of it as `stdout_limit` leaves is streamed to `events`. Returns
+    /// the result of the node and the nanoseconds it took. A cancelled node
+    /// takes the kernel down with it, as there is no stopping it otherwise.
+    fn eval(
+        &mut self,
+        compiled: &Compiled,
+        format: Format,
+        stdout: &mut String,
+        stdout_limit: Option<usize>,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<(NodeResult, u64), XXError> {
+        let start = Instant::now();
+
+        if writeln!(self.stdin, "{}", compiled.lib.display())
+            .and_then(|_| self.stdin.flush())
+            .is_err()
+        {
+            return Err(self.died(stdout));
+        }
+
+        // The restored bindings have been moved out of the store, no matter
+        // how the node turns out.
+        for name in &compiled.restored {
+            self.bindings.remove(name);
+        }
+
+        let marker = format!("{} ", self.nonce);
+        let mut node_stdout =
+            NodeStdout::new(stdout_limit.map(|limit| limit.saturating_sub(stdout.len())));
+        loop {
+            let remaining = self
+                .run_timeout
+                .checked_sub(start.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let line = match self.stdout.recv_timeout(remaining.min(CANCEL_POLL)) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) if cancel.is_cancelled() => {
+                    self.shutdown();
+                    return Err(XXError::Cancelled {
+                        partial_stdout: stdout.clone(),
+                    });
+                }
+                Err(RecvTimeoutError::Timeout) if remaining > CANCEL_POLL => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.shutdown();
+                    return Err(XXError::Timeout {
+                        phase: Phase::Run,
+                        elapsed_ms: millis(start.elapsed()),
+                        partial_stdout: stdout.clone(),
+                    });
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.died(stdout)),
+            };
+            if !line.starts_with(&marker) {
+                node_stdout.line(line.trim_end_matches('\n'), events);
+                stdout.push_str(&line);
+                continue;
+            }
+            // Drop the newline written in front of the marker
+            stdout.pop();
+            node_stdout.end(events);
+
+            let line = line[marker.len()..].trim_end();
+            if let Some(output) = line.strip_prefix("ok ") {
+                let output: NodeReturn = match serde_json::from_str(output) {
+                    Ok(output) => output,
+                    Err(error) => {
+                        return Err(XXError::RunError {
+                            stdout: stdout.clone(),
+                            stderr: format!("{}", error),
+                        })
+                    }
+                };
+                for (name, ty) in output.bindings {
+                    match nameable_type(&ty) {
+                        Some(ty) => self.bindings.insert(name, ty),
+                        None => self.bindings.remove(&name),
+                    };
+                }
+                for line in self.take_stderr().lines() {
+                    events(ExecutionEvent::Stderr(line.to_string()));
+                }
+                let result = match output.result {
+                    Some(value) => value.into_result(format),
+                    None => NodeResult::None,
+                };
+                return Ok((result, output.time_ns));
+            } else {
+                let error = line.trim_start_matches("err ");
+                let stderr = self.take_stderr();
+                let stderr = if stderr.is_empty() {
+                    serde_json::from_str(error).unwrap_or_else(|_| error.to_string())
+                } else {
+                    stderr
+                };
+                return Err(XXError::RunError {
+                    stdout: stdout.clone(),
+                    stderr,
+                });
+            }
+        }
+    }
+
+    fn take_stderr(&self) -> String {
+        std::mem::take(&mut *self.stderr.lock().unwrap())
+    }
+
+    fn shutdown(&mut self) -> (Option<ExitStatus>, String) {
+        self.dead = true;
+        process::kill_group(&self.process);
+        let status = self.process.wait().ok();
+        if let Some(reader) = self.stderr_reader.take() {
+            let _ = reader.join();
+        }
+        (status, self.take_stderr())
+    }
+
+    fn died(&mut self, stdout: &str) -> XXError {
+        let (status, stderr) = self.shutdown();
+        let stdout = stdout.to_string();
+        match status.and_then(|status| self.sandbox.violation(status, None, &stderr)) {
+            Some(limit) => XXError::LimitExceeded {
+                limit,
+                stdout,
+                stderr,
+            },
+            None => XXError::RunError { stdout, stderr },
+        }
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        if !self.dead {
+            self.shutdown();
+        }
+    }
+}
+
+fn build(
+    dir: &Path,
+    crate_dir: &Path,
+    file: &str,
+    source_map: &SourceMap,
+    timeout: Duration,
+    cancel: &Cancel,
+    events: &dyn Fn(ExecutionEvent),
+) -> Result<(), XXError> {
+    diagnostics::build(
+        Command::new("cargo")
+            .current_dir(crate_dir)
+            .env("CARGO_TARGET_DIR", dir.join("target"))
+            .env(
+                "RUSTFLAGS",
+                format!(
+                    "-C prefer-dynamic -L crate={}",
+                    dir.join("target/debug/deps").display()
+                ),
+            ),
+        Profile::Debug,
+        timeout,
+        cancel,
+        file,
+        source_map,
+        events,
+    )
+}
+
+fn node_src(
+    header: &str,
+    restored: &[(String, String)],
+    node: usize,
+    parsed: &ParsedNode,
+    persisted: &[String],
+    tables: bool,
+    edition: &str,
+) -> (String, SourceMap) {
+    let restore = restored
+        .iter()
+        .map(|(name, ty)| {
+            format!(
+                "let mut {name}: {ty} = *__store.remove({name:?}).unwrap().downcast::<{ty}>().expect(\"binding changed type\");",
+                name = name,
+                ty = ty
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let types = persisted
+        .iter()
+        .map(|name| {
+            format!(
+                "format!(\"[{{:?}},{{:?}}]\", {name:?}, __repl_type_name(&{name}))",
+                name = name
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let store = persisted
+        .iter()
+        .map(|name| format!("__store.insert({name:?}.to_string(), Box::new({name}));", name = name))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut src = SourceBuilder::new();
+    prepare_attributes(&mut src, node, parsed);
+    src.push(&format!(
+        r#"#![allow(unused_mut, unused_variables)]
+{header}
+{prelude}
+"#,
+        header = header,
+        prelude = PRELUDE,
+    ));
+    if tables {
+        src.push(TABLE_PRELUDE);
+    }
+    prepare_items(&mut src, node, parsed, true);
+    // Since the 2024 edition, `no_mangle` has to be marked as unsafe
+    let no_mangle = if edition == "2024" {
+        "#[unsafe(no_mangle)]"
+    } else {
+        "#[no_mangle]"
+    };
+    src.push(&format!(
+        r#"
+{no_mangle}
+pub fn __repl_node(__store: &mut std::collections::HashMap<String, Box<dyn std::any::Any>>) -> String {{
+    let mut node_results: Vec<String> = vec![];
+    {restore}
+    let __repl_start = std::time::Instant::now();
+"#,
+        no_mangle = no_mangle,
+        restore = restore,
+    ));
+    prepare_node(&mut src, node, parsed);
+    src.push(&format!(
+        r#"    let __repl_types: Vec<String> = vec![{types}];
+    {store}
+    format!(
+        "{{{{\"result\":{{}},\"bindings\":[{{}}],\"time_ns\":{{}}}}}}",
+        node_results.pop().unwrap(),
+        __repl_types.join(","),
+        __repl_start.elapsed().as_nanos()
+    )
+}}
+"#,
+        types = types,
+        store = store
+    ));
+    src.finish()
+}
+
+/// Every identifier occurring in the node, including ones inside of macro
+/// invocations and string literals.
+fn mentioned_idents(stmts: &[syn::Stmt]) -> HashSet<String> {
+    use quote::ToTokens;
+
+    let mut src = String::new();
+    for stmt in stmts {
+        src.push_str(&format!("{} ", stmt.into_token_stream()));
+    }
+    src.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// The bindings introduced by top-level `let` statements of the node.
+fn declared_idents(stmts: &[syn::Stmt]) -> Vec<String> {
+    fn walk(pat: &syn::Pat, idents: &mut Vec<String>) {
+        match pat {
+            syn::Pat::Ident(pat) => {
+                idents.push(pat.ident.to_string());
+                if let Some((_, pat)) = &pat.subpat {
+                    walk(pat, idents);
+                }
+            }
+            syn::Pat::Struct(pat) => for field in &pat.fields {
+                walk(&field.pat, idents);
+            },
+            syn::Pat::TupleStruct(pat) => for pat in pat.pat.front.iter().chain(&pat.pat.back) {
+                walk(pat, idents);
+            },
+            syn::Pat::Tuple(pat) => for pat in pat.front.iter().chain(&pat.back) {
+                walk(pat, idents);
+            },
+            syn::Pat::Box(pat) => walk(&pat.pat, idents),
+            syn::Pat::Ref(pat) => walk(&pat.pat, idents),
+            syn::Pat::Slice(pat) => {
+                let middle = pat.middle.as_deref();
+                for pat in pat.front.iter().chain(middle).chain(&pat.back) {
+                    walk(pat, idents);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut idents = vec![];
+    for stmt in stmts {
+        if let syn::Stmt::Local(local) = stmt {
+            for pat in &local.pats {
+                walk(pat, &mut idents);
+            }
+        }
+    }
+    idents
+}
+
+/// The names of the items of a node, which later nodes import. Macros are
+/// among them, while implementations and glob imports have no name.
+fn item_names(stmts: &[syn::Stmt]) -> Vec<String> {
+    fn walk(tree: &syn::UseTree, names: &mut Vec<String>) {
+        match tree {
+            syn::UseTree::Path(tree) => walk(&tree.tree, names),
+            syn::UseTree::Name(tree) if tree.ident != "self" => names.push(tree.ident.to_string()),
+            syn::UseTree::Rename(tree) => names.push(tree.rename.to_string()),
+            syn::UseTree::Group(tree) => for tree in &tree.items {
+                walk(tree, names);
+            },
+            _ => {}
+        }
+    }
+
+    let mut names = vec![];
+    for stmt in stmts {
+        let item = match stmt {
+            syn::Stmt::Item(item) => item,
+            _ => continue,
+        };
+        match item {
+            syn::Item::Use(item) => walk(&item.tree, &mut names),
+            syn::Item::Static(item) => names.push(item.ident.to_string()),
+            syn::Item::Const(item) => names.push(item.ident.to_string()),
+            syn::Item::Fn(item) => names.push(item.ident.to_string()),
+            syn::Item::Mod(item) => names.push(item.ident.to_string()),
+            syn::Item::Type(item) => names.push(item.ident.to_string()),
+            syn::Item::Struct(item) => names.push(item.ident.to_string()),
+            syn::Item::Enum(item) => names.push(item.ident.to_string()),
+            syn::Item::Union(item) => names.push(item.ident.to_string()),
+            syn::Item::Trait(item) => names.push(item.ident.to_string()),
+            syn::Item::Macro(syn::ItemMacro {
+                ident: Some(ident), ..
+            }) => names.push(ident.to_string()),
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Identifiers quoted in the error lines of rustc output.
+fn names_in_errors(stderr: &str) -> HashSet<String> {
+    stderr
+        .lines()
+        .filter(|line| line.starts_with("error"))
+        .flat_map(|line| line.split('`').skip(1).step_by(2))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Turns the output of `std::any::type_name` into a type which can be written
+/// in a later node. Paths into the standard library point to private modules,
+/// so they are shortened to their public reexports.
+fn nameable_type(ty: &str) -> Option<String> {
+    if ty.contains("{{") || ty.contains("__repl_node") {
+        return None;
+    }
+
+    let out = map_type_paths(ty, |path| {
+        let segments = path.split("::").collect::<Vec<_>>();
+        match segments[0] {
+            "std" | "core" | "alloc" if segments.len() > 2 => {
+                format!("std::{}::{}", segments[1], segments[segments.len() - 1])
+            }
+            _ => path.to_string(),
+        }
+    });
+    syn::parse_str::<syn::Type>(&out).ok().map(|_| out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stmts(src: &str) -> Vec<syn::Stmt> {
+        syn::parse_str::<syn::Block>(&format!("{{{}}}", src))
+            .unwrap()
+            .stmts
+    }
+
+    #[test]
+    fn std_types_are_named_by_their_reexports() {
+        assert_eq!(
+            nameable_type("alloc::vec::Vec<alloc::string::String>").unwrap(),
+            "std::vec::Vec<std::string::String>"
+        );
+        assert_eq!(
+            nameable_type("core::option::Option<(i32, &str)>").unwrap(),
+            "std::option::Option<(i32, &str)>"
+        );
+        assert_eq!(
+            nameable_type("std::collections::hash::map::HashMap<u8, [f64; 3]>").unwrap(),
+            "std::collections::HashMap<u8, [f64; 3]>"
+        );
+        assert_eq!(nameable_type("regex::Regex").unwrap(), "regex::Regex");
+    }
+
+    #[test]
+    fn closures_and_node_types_cannot_be_named() {
+        assert_eq!(nameable_type("main::{{closure}}"), None);
+        assert_eq!(nameable_type("__repl_node_3::Point"), None);
+        assert_eq!(nameable_type("<not a type"), None);
+    }
+
+    #[test]
+    fn declared_idents_are_found_in_patterns() {
+        let stmts = stmts(
+            "let a = 1;
+            let (b, [c, .., d]) = (2, [3, 4, 5]);
+            let Point { x: e, y: ref f } = p;
+            let g @ Some(h) = Some(6);
+            let &i = &7;
+            let _ = 8;
+            fn j() {}
+            a + b",
+        );
+        assert_eq!(
+            declared_idents(&stmts),
+            ["a", "b", "c", "d", "e", "f", "g", "h", "i"]
+        );
+    }
+
+    #[test]
+    fn names_are_taken_from_error_lines_only() {
+        let stderr = "error[E0425]: cannot find value `x` in this scope
+  --> src/lib.rs:3:5
+   = help: the binding `y` is declared here
+error[E0382]: use of moved value: `v`, and `w`
+warning: unused variable: `z`";
+        let names = names_in_errors(stderr);
+        let mut names = names.iter().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["v", "w", "x"]);
+    }
+}
@@ -1,5 +1,8 @@
+extern crate actix;
 extern crate actix_web;
 extern crate failure;
+extern crate futures;
+extern crate libc;
 extern crate quote;
 extern crate serde_json;
 extern crate shared;
@@ -10,232 +13,1518 @@ extern crate time;
 #[macro_use]
 extern crate serde_derive;
 
+mod build_options;
+mod cancel;
+mod dependencies;
+mod diagnostics;
+mod directives;
+mod ids;
+mod kernel;
+mod outputs;
+mod pool;
+mod process;
+mod sandbox;
+mod sessions;
+mod source_map;
+mod stream;
+mod timings;
+
+use cancel::{Cancel, Running};
+use dependencies::Dependencies;
+use directives::Directives;
+use kernel::Kernel;
+use outputs::{OutputLimits, Outputs};
+use pool::Pool;
+use process::Pipe;
+use sandbox::Sandbox;
+use sessions::Sessions;
 use shared::*;
+use source_map::{SourceBuilder, SourceMap};
 
 use actix_web::middleware::cors::Cors;
-use actix_web::{http, server, App, Json};
+use actix_web::{error, http, server, App, FutureResponse, Json};
+use futures::sync::oneshot;
+use futures::Future;
 use quote::ToTokens;
+use std::cell::RefCell;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+struct Config {
+    build_timeout: Duration,
+    run_timeout: Duration,
+    sandbox: Sandbox,
+    vendor_dir: Option<PathBuf>,
+    offline: bool,
+    backtrace: bool,
+    output_limits: OutputLimits,
+    /// How many executions run at the same time, each in a build directory
+    /// of its own.
+    concurrency: usize,
+    /// Where sessions are kept. They are lost with the server without one.
+    sessions_dir: Option<PathBuf>,
+    /// How long a session may go unused before it is deleted.
+    session_idle: Option<Duration>,
+}
+
+impl Config {
+    fn from_env() -> Config {
+        fn var<T: std::str::FromStr>(var: &str, default: T) -> T {
+            std::env::var(var)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("failed to parse {}={:?}", var, value))
+                })
+                .unwrap_or(default)
+        }
+        // Limits can be lifted by setting them to "none"
+        fn limit(name: &str, default: Option<u64>, unit: u64) -> Option<u64> {
+            match std::env::var(name) {
+                Ok(ref value) if value == "none" => None,
+                Ok(_) => Some(var(name, 0) * unit),
+                Err(_) => default.map(|limit| limit * unit),
+            }
+        }
+
+        const KIB: u64 = 1024;
+        const MIB: u64 = 1024 * 1024;
+
+        Config {
+            build_timeout: Duration::from_secs(var("REPL_BUILD_TIMEOUT", 120)),
+            run_timeout: Duration::from_secs(var("REPL_RUN_TIMEOUT", 10)),
+            sandbox: Sandbox {
+                cpu_seconds: limit("REPL_LIMIT_CPU", Some(10), 1),
+                memory_bytes: limit("REPL_LIMIT_MEMORY", Some(1024), MIB),
+                file_size_bytes: limit("REPL_LIMIT_FILE_SIZE", Some(64), MIB),
+                processes: limit("REPL_LIMIT_PROCESSES", None, 1),
+                isolate_network: var("REPL_ISOLATE_NETWORK", false),
+                seccomp: var("REPL_SECCOMP", false),
+            },
+            vendor_dir: std::env::var_os("REPL_VENDOR_DIR").map(PathBuf::from),
+            offline: var("REPL_OFFLINE", false),
+            backtrace: var("REPL_BACKTRACE", false),
+            output_limits: OutputLimits {
+                result_len: limit("REPL_MAX_RESULT_LEN", Some(100), KIB).map(|len| len as usize),
+                stdout_len: limit("REPL_MAX_STDOUT_LEN", Some(1024), KIB).map(|len| len as usize),
+            },
+            concurrency: var("REPL_CONCURRENCY", 2),
+            sessions_dir: std::env::var_os("REPL_SESSIONS_DIR").map(PathBuf::from),
+            session_idle: limit("REPL_SESSION_IDLE", Some(24 * 60 * 60), 1).map(Duration::from_secs),
+        }
+    }
+}
 
 struct Executioner {
     build_dir: PathBuf,
+    /// Shared with the other executioners of the pool.
+    target_dir: PathBuf,
+    /// The name of the package, which is different for every executioner so
+    /// their binaries don't overwrite each other in `target_dir`.
+    name: String,
+    config: Config,
+    kernel: RefCell<Option<Kernel>>,
+    /// Shared with the requests fetching the rest of truncated outputs.
+    outputs: Arc<Mutex<Outputs>>,
 }
 
 impl Executioner {
-    fn new<P: Into<PathBuf>>(build_dir: P) -> Result<Executioner, failure::Error> {
-        let build_dir: PathBuf = build_dir.into();
+    fn new(
+        build_dir: PathBuf,
+        target_dir: PathBuf,
+        name: String,
+        config: Config,
+        outputs: Arc<Mutex<Outputs>>,
+    ) -> Result<Executioner, failure::Error> {
         let src_dir = build_dir.join("src");
         std::fs::create_dir_all(&src_dir)?;
+        std::fs::create_dir_all(build_dir.join(".cargo"))?;
+        std::fs::write(
+            build_dir.join(".cargo/config.toml"),
+            dependencies::cargo_config(config.vendor_dir.as_ref(), config.offline),
+        )?;
         std::fs::write(
             build_dir.join("Cargo.toml"),
-            r#"[package]
-name = "runtree"
-version = "0.1.0"
-
-[dependencies]
-"#,
+            runtree_manifest(&name, &Dependencies::default(), "2015"),
         )?;
         std::fs::write(src_dir.join("main.rs"), "")?;
-        Ok(Executioner { build_dir })
+        Ok(Executioner {
+            build_dir,
+            target_dir,
+            name,
+            outputs,
+            config,
+            kernel: RefCell::new(None),
+        })
     }
-    fn execute(&self, execution: &Execution) -> Result<ExecutionResult, XXError> {
+    /// Runs the nodes of `execution`, reporting the progress to `events` as
+    /// it goes.
+    fn execute(
+        &self,
+        execution: &Execution,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<ExecutionResult, XXError> {
+        build_options::validate(&execution.parameters.build)?;
+        if execution.parameters.persistent {
+            return self.execute_persistent(execution, events);
+        }
+        // A regular run starts from scratch, so any persistent state is
+        // discarded. Only sessions run persistent nodes, in an executioner of
+        // their own, so this is never someone else's kernel.
+        self.kernel.borrow_mut().take();
+
         let build_dir = self
             .build_dir
             .canonicalize()
             .expect("failed to canonicalize build_dir");
 
-        let run_dir = execution.run_dir.as_ref().unwrap_or(&build_dir);
+        // Every run gets a private scratch directory, unless told where to run
+        let scratch_dir = tempfile::tempdir().expect("failed to create scratch dir");
+        let run_dir = execution
+            .run_dir
+            .as_ref()
+            .map_or(scratch_dir.path(), |run_dir| run_dir.as_path());
 
         // Write to file
-        let src = execution.prepare_src()?;
+        let marker = format!("__repl_{}", time::precise_time_ns());
+        let separator = format!("\n{}\n", marker);
+        let parsed = execution.parse_nodes()?;
+        // Benchmarks are only worth anything with optimizations, so runs with
+        // one are built in release whatever the profile chosen
+        let options = &BuildOptions {
+            profile: if parsed.iter().any(|node| node.directives.bench.is_some()) {
+                Profile::Release
+            } else {
+                execution.parameters.build.profile
+            },
+            ..execution.parameters.build.clone()
+        };
+        let dependencies = Dependencies::collect(&parsed)?;
+        let (src, source_map) = prepare_src(&parsed, &marker, dependencies.has_tables());
 
         std::fs::write(build_dir.join("src/main.rs"), src).expect("failed to write file");
+        std::fs::write(
+            build_dir.join("Cargo.toml"),
+            runtree_manifest(&self.name, &dependencies, build_options::edition(options)),
+        ).expect("failed to write file");
 
-        let start_time = time::PreciseTime::now();
+        let start_time = Instant::now();
+
+        // Resolve dependencies
+        dependencies.fetch(&build_dir, self.config.build_timeout, &execution.cancel, events)?;
 
         // Build
-        let build_output = std::process::Command::new("cargo")
-            .current_dir(&build_dir)
-            .arg("build")
-            .output()
-            .expect("failed to build");
-        let build_time = time::PreciseTime::now();
-        if !build_output.status.success() {
-            let error = String::from_utf8(build_output.stderr).unwrap();
-            return Err(XXError::BuildError { error });
-        }
-
-        // Run
-        let output = std::process::Command::new(build_dir.join("./target/debug/runtree"))
-            .current_dir(run_dir)
-            .output()
-            .expect("failed to execute");
-        let run_time = time::PreciseTime::now();
-
-        let stdout = String::from_utf8(output.stdout).expect("failed to parse stdout to utf8");
-
-        if !output.status.success() {
-            let stderr = String::from_utf8(output.stderr).unwrap();
+        diagnostics::build(
+            build_options::apply(
+                Command::new("cargo")
+                    .current_dir(&build_dir)
+                    .env("CARGO_TARGET_DIR", &self.target_dir),
+                options,
+            ),
+            options.profile,
+            self.config.build_timeout,
+            &execution.cancel,
+            "src/main.rs",
+            &source_map,
+            events,
+        )?;
+        let build_time = Instant::now();
+        let compile_timings = timings::read(&self.target_dir, &self.name);
+
+        // Run. Everything after the marker of the last node is the results.
+        // A node has written its result by the time its marker is printed,
+        // so it is reported right away.
+        let node_count = execution.parameters.nodes.len();
+        let mut markers = 0;
+        let mut node_stdout = NodeStdout::new(self.config.output_limits.stdout_len);
+        let results_file = tempfile::NamedTempFile::new().expect("failed to create results file");
+        let mut results_reader = std::io::BufReader::new(
+            results_file.reopen().expect("failed to open results file"),
+        );
+        let mut nodes = vec![];
+        let mut printed = 0;
+        let binary = self
+            .target_dir
+            .join(options.profile.to_string())
+            .join(&self.name);
+        let (output, cpu_time) = process::output_timed(
+            self.config
+                .sandbox
+                .apply(&mut Command::new(binary), run_dir)
+                .env("REPL_RESULTS", results_file.path())
+                .env("RUST_BACKTRACE", if self.config.backtrace { "1" } else { "0" }),
+            self.config.run_timeout,
+            &execution.cancel,
+            &mut |pipe, line| match pipe {
+                Pipe::Stdout if line == marker => {
+                    node_stdout.end(events);
+                    let stdout = node_stdout.take();
+                    if nodes.len() == markers {
+                        if let Some(result @ ResultLine::Node { .. }) =
+                            read_result(&mut results_reader)
+                        {
+                            let output = node_output(
+                                &mut lock(&self.outputs),
+                                Some(result),
+                                &parsed[markers],
+                                &source_map,
+                                &stdout,
+                                printed,
+                            );
+                            printed += stdout.len();
+                            events(ExecutionEvent::Node {
+                                node: markers,
+                                output: output.clone(),
+                            });
+                            nodes.push(output);
+                        }
+                    }
+                    markers += 1;
+                }
+                Pipe::Stdout if markers < node_count => node_stdout.line(line, events),
+                Pipe::Stdout => {}
+                Pipe::Stderr => events(ExecutionEvent::Stderr(line.to_string())),
+            },
+        ).map_err(|mut overrun| {
+            overrun.stdout = overrun.stdout.replace(&separator, "");
+            overrun.into_error(Phase::Run)
+        })?;
+        let run_time = Instant::now();
+        // The program might have stopped in the middle of a node
+        node_stdout.end(events);
+
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let results = read_results(results_file.path());
+
+        // A panicking node fails the program, but the nodes before it have
+        // results all the same.
+        let panicked = match &results {
+            Ok(results) => results.iter().any(|result| match result {
+                ResultLine::Panic { .. } => true,
+                ResultLine::Node { .. } => false,
+            }),
+            Err(_) => false,
+        };
+        if !output.status.success() && !panicked {
+            let stdout = stdout.replace(&separator, "");
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            if let Some(limit) = self
+                .config
+                .sandbox
+                .violation(output.status, Some(cpu_time), &stderr) {
+                return Err(XXError::LimitExceeded {
+                    limit,
+                    stdout,
+                    stderr,
+                });
+            }
             return Err(XXError::RunError { stdout, stderr });
         }
 
-        // Parse output
-        let x = stdout.rfind(']').expect("failed to parse debug output");
-        let n: usize = stdout[x + 1..stdout.len() - 1].parse().unwrap();
-        #[derive(Debug, Deserialize)]
-        struct ParsedOutput(Vec<Option<String>>);
-        let parsed_output: ParsedOutput = serde_json::from_str(&stdout[x - n - 1..x + 1]).unwrap();
+        // A program exiting early leaves out the nodes after the one exiting,
+        // which then has no result either. The nodes which were reported
+        // during the run are kept as they were.
+        let node_stdouts = stdout.split(&separator).take(node_count).collect::<Vec<_>>();
+        let mut results = results?.into_iter().skip(nodes.len());
 
-        let fix_time = |t: time::Duration| (t.num_seconds() * 1000 + t.num_milliseconds()) as u32;
+        let mut outputs = lock(&self.outputs);
+        for (i, stdout) in node_stdouts.iter().enumerate().skip(nodes.len()) {
+            let output = node_output(
+                &mut outputs,
+                results.next(),
+                &parsed[i],
+                &source_map,
+                stdout,
+                printed,
+            );
+            printed += stdout.len();
+            events(ExecutionEvent::Node {
+                node: i,
+                output: output.clone(),
+            });
+            nodes.push(output);
+        }
+        let (stdout, stdout_truncated) = outputs.stdout(node_stdouts.concat());
+        drop(outputs);
 
         Ok(ExecutionResult {
-            build_time: fix_time(start_time.to(build_time)),
-            run_time: fix_time(build_time.to(run_time)),
-            nodes: parsed_output
-                .0
-                .into_iter()
-                .map(|s| match s {
-                    Some(s) => NodeResult::String(s),
-                    None => NodeResult::None,
-                })
-                .collect(),
-            stdout: (&stdout[0..x - n - 1]).to_string(),
+            build_time: process::millis(build_time - start_time),
+            run_time: process::millis(run_time - build_time),
+            timing: Timing {
+                build_ns: process::nanos(build_time - start_time),
+                compile_ns: compile_timings.compile.map(process::nanos),
+                codegen_ns: compile_timings.codegen.map(process::nanos),
+                link_ns: compile_timings.link.map(process::nanos),
+                run_ns: process::nanos(run_time - build_time),
+                run_cpu_ns: Some(process::nanos(cpu_time)),
+            },
+            profile: options.profile,
+            nodes,
+            stdout,
+            stdout_truncated,
+            queue_position: None,
         })
     }
+    fn execute_persistent(
+        &self,
+        execution: &Execution,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<ExecutionResult, XXError> {
+        build_options::validate_persistent(&execution.parameters.build)?;
+        let build_dir = self
+            .build_dir
+            .canonicalize()
+            .expect("failed to canonicalize build_dir");
+
+        let parsed = execution.parse_nodes()?;
+        reject_bench(
+            &parsed,
+            "Benchmarks need a release build, which persistent nodes do not have",
+        )?;
+        let dependencies = Dependencies::collect(&parsed)?;
+        let nodes = execution
+            .parameters
+            .nodes
+            .iter()
+            .map(|node| node.content.clone())
+            .zip(parsed)
+            .collect::<Vec<_>>();
+
+        let mut kernel = self.kernel.borrow_mut();
+        if kernel.is_none() {
+            let run_dir = execution.run_dir.as_deref();
+            *kernel = Some(Kernel::start(
+                &build_dir,
+                run_dir,
+                &self.config,
+                &execution.cancel,
+                events,
+            )?);
+        }
+        let result = kernel.as_mut().unwrap().run(
+            nodes,
+            dependencies,
+            &execution.parameters.build,
+            &self.outputs,
+            &execution.cancel,
+            events,
+        );
+        if kernel.as_ref().is_some_and(|kernel| kernel.is_dead()) {
+            *kernel = None;
+        }
+        result
+    }
+}
+
+/// Fails with `reason` at the first `bench` directive of the nodes, if they
+/// have one.
+fn reject_bench(parsed: &[ParsedNode], reason: &str) -> Result<(), XXError> {
+    let bench = parsed
+        .iter()
+        .enumerate()
+        .filter_map(|(i, node)| node.directives.bench.map(|span| (i, node, span)))
+        .next();
+    match bench {
+        Some((node, parsed, span)) => Err(XXError::ParseNode {
+            src: parsed.source.clone(),
+            error: reason.to_string(),
+            node,
+            span,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Passes the lines printed by a node on to `events`. They are passed on one
+/// behind, as the last line ends with the newline printed in front of the
+/// marker following the node. Once `remaining` runs out, the rest is dropped.
+/// What the current node printed is collected as well, until it is `take`n.
+struct NodeStdout {
+    previous_line: Option<String>,
+    remaining: Option<usize>,
+    node: String,
+}
+
+impl NodeStdout {
+    fn new(limit: Option<usize>) -> NodeStdout {
+        NodeStdout {
+            previous_line: None,
+            remaining: limit,
+            node: String::new(),
+        }
+    }
+    fn line(&mut self, line: &str, events: &dyn Fn(ExecutionEvent)) {
+        if let Some(previous_line) = self.previous_line.replace(line.to_string()) {
+            self.node.push_str(&previous_line);
+            self.node.push('\n');
+            self.send(previous_line, events);
+        }
+    }
+    fn end(&mut self, events: &dyn Fn(ExecutionEvent)) {
+        if let Some(previous_line) = self.previous_line.take() {
+            self.node.push_str(&previous_line);
+            if !previous_line.is_empty() {
+                self.send(previous_line, events);
+            }
+        }
+    }
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.node)
+    }
+    fn send(&mut self, line: String, events: &dyn Fn(ExecutionEvent)) {
+        match &mut self.remaining {
+            Some(0) => {}
+            Some(remaining) => {
+                let line = outputs::preview(&line, *remaining);
+                *remaining = remaining.saturating_sub(line.len() + 1);
+                events(ExecutionEvent::Stdout(line.to_string()));
+            }
+            None => events(ExecutionEvent::Stdout(line)),
+        }
+    }
+}
+
+/// A line of the results written by the program.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ResultLine {
+    Panic {
+        panic: PanicRecord,
+    },
+    /// A node which finished, and how long it took
+    Node {
+        result: Option<NodeValue>,
+        time_ns: u64,
+    },
+}
+
+/// The result of a node, and its type. The value is missing when the type
+/// cannot be formatted the way the node asked for, and `rich` is there
+/// instead of it for types implementing `ReplRender` and for benchmarks.
+#[derive(Debug, Deserialize)]
+struct NodeValue {
+    value: Option<String>,
+    rich: Option<NodeResult>,
+    type_name: String,
+}
+
+impl NodeValue {
+    fn into_result(self, format: Format) -> NodeResult {
+        if let Some(rich) = self.rich {
+            return rich;
+        }
+        let type_name = short_type_name(&self.type_name);
+        let value = self.value.unwrap_or_else(|| {
+            let implementation = match format {
+                Format::Debug | Format::PrettyDebug => "Debug",
+                Format::Display => "Display",
+            };
+            format!("<{}: no {} impl>", type_name, implementation)
+        });
+        NodeResult::String {
+            value,
+            format,
+            type_name,
+        }
+    }
+}
+
+/// Leaves out the paths in the name of a type, so
+/// `alloc::vec::Vec<alloc::string::String>` becomes `Vec<String>`.
+fn short_type_name(ty: &str) -> String {
+    map_type_paths(ty, |path| path.rsplit("::").next().unwrap_or("").to_string())
+}
+
+/// Replaces every path in the name of a type with what `map` makes of it,
+/// keeping everything in between.
+fn map_type_paths(ty: &str, map: impl Fn(&str) -> String) -> String {
+    let mut out = String::new();
+    let mut rest = ty;
+    while let Some(c) = rest.chars().next() {
+        if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+                .unwrap_or(rest.len());
+            out.push_str(&map(&rest[..end]));
+            rest = &rest[end..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// A panic, as recorded by the panic hook of the program.
+#[derive(Deserialize)]
+struct PanicRecord {
+    message: String,
+    location: Option<PanicLocation>,
+    backtrace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PanicLocation {
+    file: String,
+    line: usize,
+    column: usize,
+}
+
+impl PanicRecord {
+    fn into_result(self, source_map: &SourceMap) -> NodeResult {
+        let location = self
+            .location
+            .filter(|location| location.file == "src/main.rs")
+            .and_then(|location| {
+                source_map.lookup(LineColumn {
+                    line: location.line,
+                    column: location.column,
+                })
+            });
+        NodeResult::Panic {
+            message: self.message,
+            node: location.map(|(node, _)| node),
+            location: location.map(|(_, location)| location),
+            backtrace: self.backtrace,
+        }
+    }
+}
+
+/// Reads the results written by the program, a line of JSON for every node it
+/// got through, and the panic of the node it did not get through.
+/// Reads the next line of the results, which the program has written in
+/// full before printing the marker of its node.
+fn read_result(reader: &mut impl std::io::BufRead) -> Option<ResultLine> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(n) if n > 0 && line.ends_with('\n') => serde_json::from_str(&line).ok(),
+        _ => None,
+    }
+}
+
+/// The output of a node, from its line of the results and what it printed
+/// after `printed` bytes printed by the nodes before it.
+fn node_output(
+    outputs: &mut Outputs,
+    result: Option<ResultLine>,
+    parsed: &ParsedNode,
+    source_map: &SourceMap,
+    stdout: &str,
+    printed: usize,
+) -> NodeOutput {
+    let (result, time_ns) = match result {
+        Some(ResultLine::Node {
+            result: Some(value),
+            time_ns,
+        }) => (value.into_result(parsed.directives.format), Some(time_ns)),
+        Some(ResultLine::Node { result: None, time_ns }) => (NodeResult::None, Some(time_ns)),
+        Some(ResultLine::Panic { panic }) => (panic.into_result(source_map), None),
+        None => (NodeResult::None, None),
+    };
+    let (stdout, stdout_truncated) = outputs.node_stdout(stdout, printed);
+    NodeOutput {
+        result: outputs.result(result),
+        stdout,
+        stdout_truncated,
+        time_ns,
+    }
+}
+
+fn read_results(path: &std::path::Path) -> Result<Vec<ResultLine>, XXError> {
+    let results = std::fs::read_to_string(path).map_err(|error| XXError::ResultError {
+        error: format!("{}", error),
+    })?;
+    results
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).map_err(|error| XXError::ResultError {
+                error: format!("{} in {:?}", error, line),
+            })
+        })
+        .collect()
+}
+
+fn runtree_manifest(name: &str, dependencies: &Dependencies, edition: &str) -> String {
+    format!(
+        r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "{}"
+
+[dependencies]
+{}"#,
+        name,
+        edition,
+        dependencies.to_toml()
+    )
 }
 
 struct Execution {
     run_dir: Option<PathBuf>,
     parameters: ExecutionParameters,
+    cancel: Cancel,
 }
 
 impl Execution {
-    fn prepare_src(&self) -> Result<String, XXError> {
-        let parsed_nodes = self
-            .parameters
+    fn parse_nodes(&self) -> Result<Vec<ParsedNode>, XXError> {
+        self.parameters
             .nodes
             .iter()
             .enumerate()
             .map(|(i, node)| {
-                let src = format!("{{{}}}", node.content);
+                // Inner attributes are not allowed in a block, so they are
+                // replaced by spaces, keeping the positions of the rest.
+                let attributes = source_map::inner_attributes(&node.content);
+                let mut content = node.content.clone();
+                for range in &attributes {
+                    let blank = node.content[range.clone()]
+                        .chars()
+                        .map(|c| if c == '\n' { "\n".to_string() } else { " ".repeat(c.len_utf8()) })
+                        .collect::<String>();
+                    content.replace_range(range.clone(), &blank);
+                }
+
+                let src = format!("{{{}\n}}", content);
                 let parsed =
                     syn::parse_str::<syn::Block>(&src).map_err(|error| XXError::ParseNode {
-                        src,
+                        src: node.content.clone(),
                         error: format!("{}", error),
                         node: i,
+                        span: source_map::parse_error_span(&content),
                     })?;
-                Ok(parsed.stmts)
+                Ok(ParsedNode {
+                    directives: Directives::parse(i, &node.content)?,
+                    source: node.content.clone(),
+                    content,
+                    attributes,
+                    stmts: parsed.stmts,
+                })
             })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        let code = parsed_nodes
-            .into_iter()
-            .map(|stmts| {
-                let len = stmts.len();
-                let mut did_push = false;
-                let mut block = stmts
-                    .into_iter()
-                    .enumerate()
-                    .map(|(i, stmt)| {
-                        let src = match stmt {
-                            syn::Stmt::Local(local) => format!("{}", local.into_token_stream()),
-                            syn::Stmt::Item(item) => format!("{}", item.into_token_stream()),
-                            syn::Stmt::Semi(expr, _) => format!("{};", expr.into_token_stream()),
-                            syn::Stmt::Expr(expr) => if len - 1 == i {
-                                did_push = true;
-                                format!(
-                                    "node_results.push(({}).to_debugable());",
-                                    expr.into_token_stream()
-                                )
-                            } else {
-                                panic!("expression found not at end of node");
-                            },
-                        };
-                        src
-                    })
-                    .collect::<Vec<_>>();
-                if !did_push {
-                    block.push("node_results.push(\"null\".to_string());".to_string());
+            .collect()
+    }
+}
+
+/// Generates the program, which prints `marker` on its own line after each
+/// node, so the output of the nodes can be told apart.
+fn prepare_src(nodes: &[ParsedNode], marker: &str, tables: bool) -> (String, SourceMap) {
+    let mut src = SourceBuilder::new();
+    for (i, node) in nodes.iter().enumerate() {
+        prepare_attributes(&mut src, i, node);
+    }
+    src.push(PRELUDE);
+    if tables {
+        src.push(TABLE_PRELUDE);
+    }
+    src.push(
+        r#"
+/// The results of the nodes are written to a file of their own, so nothing
+/// printed by the nodes can get in the way.
+struct __ReplResults(std::fs::File);
+
+impl __ReplResults {
+    fn open() -> __ReplResults {
+        let path = std::env::var_os("REPL_RESULTS").expect("REPL_RESULTS is not set");
+        __ReplResults(std::fs::File::create(path).expect("failed to create results file"))
+    }
+
+    fn write(&mut self, result: Option<String>, time: std::time::Duration) {
+        use std::io::Write;
+        let result = result.unwrap_or_else(|| "null".to_string());
+        writeln!(self.0, "{{\"result\":{},\"time_ns\":{}}}", result, time.as_nanos())
+            .expect("failed to write result");
+    }
+}
+
+/// Records a panic of the main thread as the result of the node it happened
+/// in, before the program goes down.
+fn __repl_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        use std::io::Write;
+        if std::thread::current().name() == Some("main") {
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<Any>".to_string());
+            let location = info.location().map_or("null".to_string(), |location| {
+                format!(
+                    "{{\"file\":{},\"line\":{},\"column\":{}}}",
+                    __repl_json_string(location.file()),
+                    location.line(),
+                    location.column()
+                )
+            });
+            let backtrace = std::backtrace::Backtrace::capture();
+            let backtrace = match backtrace.status() {
+                std::backtrace::BacktraceStatus::Captured => {
+                    __repl_json_string(&backtrace.to_string())
                 }
-                block.join("\n")
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
+                _ => "null".to_string(),
+            };
+            let path = std::env::var_os("REPL_RESULTS").expect("REPL_RESULTS is not set");
+            if let Ok(mut file) = std::fs::OpenOptions::new().append(true).open(path) {
+                let _ = writeln!(
+                    file,
+                    "{{\"panic\":{{\"message\":{},\"location\":{},\"backtrace\":{}}}}}",
+                    __repl_json_string(&message),
+                    location,
+                    backtrace
+                );
+            }
+        }
+        default_hook(info);
+    }));
+}
+"#,
+    );
+    for (i, node) in nodes.iter().enumerate() {
+        prepare_items(&mut src, i, node, false);
+    }
+    src.push(
+        r#"
+fn main() {
+    __repl_panic_hook();
+    let mut __repl_results = __ReplResults::open();
+    let mut node_results = vec![];
+    {
+"#,
+    );
+    for (i, node) in nodes.iter().enumerate() {
+        src.push("let __repl_start = std::time::Instant::now();\n");
+        prepare_node(&mut src, i, node);
+        src.push(&format!(
+            "__repl_results.write(node_results.pop(), __repl_start.elapsed());\nprintln!(\"\\n{}\");\n",
+            marker
+        ));
+    }
+    src.push("    }\n}\n");
+
+    src.finish()
+}
+
+const PRELUDE: &str = r#"
+/// Output shown in place of the `Debug` output of a value, for types
+/// implementing `ReplRender`.
+#[allow(dead_code)]
+enum Rich {
+    Html(String),
+    Svg(String),
+    /// The bytes of a PNG image
+    Png(Vec<u8>),
+    Markdown(String),
+    Table {
+        columns: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+}
+
+#[allow(dead_code)]
+trait ReplRender {
+    fn repl_render(&self) -> Rich;
+}
+
+impl Rich {
+    fn to_json(&self) -> String {
+        fn strings(strings: &[String]) -> String {
+            let strings = strings.iter().map(|s| __repl_json_string(s)).collect::<Vec<_>>();
+            format!("[{}]", strings.join(","))
+        }
+        match self {
+            Rich::Html(html) => format!("{{\"Html\":{}}}", __repl_json_string(html)),
+            Rich::Svg(svg) => format!("{{\"Svg\":{}}}", __repl_json_string(svg)),
+            Rich::Png(png) => format!("{{\"Png\":\"{}\"}}", __repl_base64(png)),
+            Rich::Markdown(markdown) => {
+                format!("{{\"Markdown\":{}}}", __repl_json_string(markdown))
+            }
+            Rich::Table { columns, rows } => {
+                let rows = rows.iter().map(|row| strings(row)).collect::<Vec<_>>();
+                format!(
+                    "{{\"Table\":{{\"columns\":{},\"rows\":[{}]}}}}",
+                    strings(columns),
+                    rows.join(",")
+                )
+            }
+        }
+    }
+}
+
+/// Wraps the value of a node, which is formatted by calling a method on
+/// `&&&&&__ReplValue(value)`. Method lookup tries the impls for
+/// `&&&&__ReplValue` first, then the ones for `&&&__ReplValue` and so on, so
+/// `()` is told apart from everything else, the impls for `ReplRender` types
+/// are preferred over tables, tables over `Debug` and `Display`, and those
+/// over the fallbacks.
+///
+/// The methods return the fields of the value in the results of the program,
+/// or nothing for `()`, which is no value at all.
+struct __ReplValue<'a, T: ?Sized + 'a>(&'a T);
+
+trait Unit {
+    fn to_debugable(&self) -> String;
+    fn to_pretty_debugable(&self) -> String;
+    fn to_displayable(&self) -> String;
+}
+
+impl<'a, 'b, 'c, 'd, 'e> Unit for &'e &'d &'c &'b __ReplValue<'a, ()> {
+    fn to_debugable(&self) -> String {
+        String::new()
+    }
+    fn to_pretty_debugable(&self) -> String {
+        String::new()
+    }
+    fn to_displayable(&self) -> String {
+        String::new()
+    }
+}
+
+trait Renderable {
+    fn to_debugable(&self) -> String;
+    fn to_pretty_debugable(&self) -> String;
+    fn to_displayable(&self) -> String;
+}
+
+impl<'a, 'b, 'c, 'd, T: ?Sized> Renderable for &'d &'c &'b __ReplValue<'a, T>
+    where T: ReplRender
+{
+    fn to_debugable(&self) -> String {
+        format!("\"rich\":{}", self.0.repl_render().to_json())
+    }
+    fn to_pretty_debugable(&self) -> String {
+        self.to_debugable()
+    }
+    fn to_displayable(&self) -> String {
+        self.to_debugable()
+    }
+}
 
-        let src = format!(
-            r#"
-trait Debugable {{
+trait Debugable {
     fn to_debugable(&self) -> String;
-}}
+    fn to_pretty_debugable(&self) -> String;
+}
 
-impl<T> Debugable for T
+impl<'a, 'b, T: ?Sized> Debugable for &'b __ReplValue<'a, T>
     where T: std::fmt::Debug
-{{
-    fn to_debugable(&self) -> String {{
-        format!("{{:?}}", format!("{{:?}}", self))
-    }}
-}}
+{
+    fn to_debugable(&self) -> String {
+        format!("\"value\":{}", __repl_json_string(&format!("{:?}", self.0)))
+    }
+    fn to_pretty_debugable(&self) -> String {
+        format!("\"value\":{}", __repl_json_string(&format!("{:#?}", self.0)))
+    }
+}
 
-fn main() {{
-    let mut node_results = vec![];
-    {{
-        {}
-    }}
-    let out = node_results
-        .into_iter()
-        .map(|x| format!("{{}}", x))
-        .collect::<Vec<_>>()
-        .join(",");
-    println!("[{{}}]{{}}", out, out.len());
-}}
-            "#,
-            code
-        );
+trait Displayable {
+    fn to_displayable(&self) -> String;
+}
+
+impl<'a, 'b, T: ?Sized> Displayable for &'b __ReplValue<'a, T>
+    where T: std::fmt::Display
+{
+    fn to_displayable(&self) -> String {
+        format!("\"value\":{}", __repl_json_string(&format!("{}", self.0)))
+    }
+}
+
+trait NotFormattable {
+    fn to_debugable(&self) -> String;
+    fn to_pretty_debugable(&self) -> String;
+    fn to_displayable(&self) -> String;
+}
+
+impl<'a, T: ?Sized> NotFormattable for __ReplValue<'a, T> {
+    fn to_debugable(&self) -> String {
+        "\"value\":null".to_string()
+    }
+    fn to_pretty_debugable(&self) -> String {
+        "\"value\":null".to_string()
+    }
+    fn to_displayable(&self) -> String {
+        "\"value\":null".to_string()
+    }
+}
+
+fn __repl_type_name<T: ?Sized>(_: &T) -> &'static str {
+    std::any::type_name::<T>()
+}
 
-        Ok(src)
+fn __repl_result(fields: String, type_name: &str) -> String {
+    if fields.is_empty() {
+        return "null".to_string();
     }
+    format!("{{{},\"type_name\":{}}}", fields, __repl_json_string(type_name))
+}
+
+/// Runs `f` over and over in samples of many iterations, after warming up,
+/// and returns how long an iteration takes.
+fn __repl_bench<T, F: FnMut() -> T>(mut f: F) -> String {
+    use std::time::{Duration, Instant};
+    const WARM_UP: Duration = Duration::from_millis(300);
+    const SAMPLE: Duration = Duration::from_millis(10);
+    const MEASURE: Duration = Duration::from_secs(1);
+    const MIN_SAMPLES: usize = 5;
+    const MAX_SAMPLES: usize = 100;
+
+    // Grow the samples until they take long enough to be measured
+    let mut batch = 1u64;
+    let warm_up = Instant::now();
+    while warm_up.elapsed() < WARM_UP {
+        let start = Instant::now();
+        for _ in 0..batch {
+            std::hint::black_box(f());
+        }
+        if start.elapsed() < SAMPLE {
+            batch *= 2;
+        }
+    }
+
+    let mut samples = vec![];
+    let measure = Instant::now();
+    while samples.len() < MAX_SAMPLES
+        && (samples.len() < MIN_SAMPLES || measure.elapsed() < MEASURE)
+    {
+        let start = Instant::now();
+        for _ in 0..batch {
+            std::hint::black_box(f());
+        }
+        samples.push(start.elapsed().as_nanos() as f64 / batch as f64);
+    }
+
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let variance = samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = samples.len() / 2;
+    let median = if samples.len() % 2 == 0 {
+        (samples[middle - 1] + samples[middle]) / 2.0
+    } else {
+        samples[middle]
+    };
+    let fields = format!(
+        "\"rich\":{{\"Bench\":{{\"mean\":{},\"median\":{},\"stddev\":{},\"iterations\":{}}}}}",
+        mean,
+        median,
+        variance.sqrt(),
+        samples.len() as u64 * batch
+    );
+    __repl_result(fields, std::any::type_name::<T>())
+}
+
+fn __repl_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn __repl_json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+"#;
+
+/// Renders lists and maps of records as tables, for crates depending on
+/// `serde`. A record is anything serialized as a struct, tuple or map.
+const TABLE_PRELUDE: &str = r#"
+extern crate serde as __repl_serde;
+extern crate serde_json as __repl_serde_json;
+
+trait Tabular {
+    fn to_debugable(&self) -> String;
+}
+
+impl<'a, 'b, 'c, T> Tabular for &'c &'b __ReplValue<'a, Vec<T>>
+    where T: __repl_serde::Serialize + std::fmt::Debug
+{
+    fn to_debugable(&self) -> String {
+        __repl_list_table(self.0)
+    }
+}
+
+impl<'a, 'b, 'c, 'd, T> Tabular for &'c &'b __ReplValue<'a, &'d [T]>
+    where T: __repl_serde::Serialize + std::fmt::Debug
+{
+    fn to_debugable(&self) -> String {
+        __repl_list_table(self.0)
+    }
+}
+
+impl<'a, 'b, 'c, T, const N: usize> Tabular for &'c &'b __ReplValue<'a, [T; N]>
+    where T: __repl_serde::Serialize + std::fmt::Debug
+{
+    fn to_debugable(&self) -> String {
+        __repl_list_table(self.0)
+    }
+}
+
+impl<'a, 'b, 'c, K, V, S> Tabular for &'c &'b __ReplValue<'a, std::collections::HashMap<K, V, S>>
+    where K: __repl_serde::Serialize + std::fmt::Debug, V: __repl_serde::Serialize + std::fmt::Debug
+{
+    fn to_debugable(&self) -> String {
+        __repl_map_table(self.0.iter(), self.0)
+    }
+}
+
+impl<'a, 'b, 'c, K, V> Tabular for &'c &'b __ReplValue<'a, std::collections::BTreeMap<K, V>>
+    where K: __repl_serde::Serialize + std::fmt::Debug, V: __repl_serde::Serialize + std::fmt::Debug
+{
+    fn to_debugable(&self) -> String {
+        __repl_map_table(self.0.iter(), self.0)
+    }
+}
+
+fn __repl_list_table<T>(list: &[T]) -> String
+    where T: __repl_serde::Serialize + std::fmt::Debug
+{
+    let rows = list.iter().map(__repl_row).collect::<Option<Vec<_>>>();
+    match rows {
+        Some(ref rows) if !rows.is_empty() => __repl_table(rows),
+        _ => format!("\"value\":{}", __repl_json_string(&format!("{:?}", list))),
+    }
+}
+
+fn __repl_map_table<'a, K, V, M>(entries: impl Iterator<Item = (&'a K, &'a V)>, map: &M) -> String
+    where K: __repl_serde::Serialize + 'a, V: __repl_serde::Serialize + 'a, M: std::fmt::Debug
+{
+    let rows = entries
+        .map(|(key, value)| {
+            let mut row = vec![("key".to_string(), __repl_cell(key))];
+            match __repl_row(value) {
+                Some(fields) => row.extend(fields),
+                None => row.push(("value".to_string(), __repl_cell(value))),
+            }
+            row
+        })
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        format!("\"value\":{}", __repl_json_string(&format!("{:?}", map)))
+    } else {
+        __repl_table(&rows)
+    }
+}
+
+/// Lines the rows up by their column names, in the order they are first seen.
+fn __repl_table(rows: &[Vec<(String, String)>]) -> String {
+    let mut columns: Vec<String> = vec![];
+    for row in rows {
+        for (column, _) in row {
+            if !columns.contains(column) {
+                columns.push(column.clone());
+            }
+        }
+    }
+    let rows = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| {
+                    row.iter()
+                        .find(|(name, _)| name == column)
+                        .map_or(String::new(), |(_, cell)| cell.clone())
+                })
+                .collect()
+        })
+        .collect();
+    format!("\"rich\":{}", Rich::Table { columns, rows }.to_json())
+}
+
+fn __repl_cell<T: __repl_serde::Serialize + ?Sized>(value: &T) -> String {
+    match __repl_serde_json::to_value(value) {
+        Ok(__repl_serde_json::Value::String(s)) => s,
+        Ok(value) => value.to_string(),
+        Err(error) => format!("<{}>", error),
+    }
+}
+
+/// The fields of a record, or `None` if `value` is not one.
+fn __repl_row<T: __repl_serde::Serialize + ?Sized>(value: &T) -> Option<Vec<(String, String)>> {
+    value.serialize(__ReplRowSerializer).ok()
+}
+
+struct __ReplRowSerializer;
+
+#[derive(Default)]
+struct __ReplRow {
+    fields: Vec<(String, String)>,
+    key: Option<String>,
 }
 
+macro_rules! __repl_not_a_record {
+    ($($method:ident($($ty:ty),*);)*) => {
+        $(fn $method(self, $(_: $ty),*) -> Result<Self::Ok, Self::Error> {
+            Err(__repl_serde::ser::Error::custom("not a record"))
+        })*
+    };
+}
+
+impl __repl_serde::Serializer for __ReplRowSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = __repl_serde_json::Error;
+    type SerializeSeq = __repl_serde::ser::Impossible<Self::Ok, Self::Error>;
+    type SerializeTuple = __ReplRow;
+    type SerializeTupleStruct = __ReplRow;
+    type SerializeTupleVariant = __repl_serde::ser::Impossible<Self::Ok, Self::Error>;
+    type SerializeMap = __ReplRow;
+    type SerializeStruct = __ReplRow;
+    type SerializeStructVariant = __repl_serde::ser::Impossible<Self::Ok, Self::Error>;
+
+    __repl_not_a_record! {
+        serialize_bool(bool);
+        serialize_i8(i8);
+        serialize_i16(i16);
+        serialize_i32(i32);
+        serialize_i64(i64);
+        serialize_u8(u8);
+        serialize_u16(u16);
+        serialize_u32(u32);
+        serialize_u64(u64);
+        serialize_f32(f32);
+        serialize_f64(f64);
+        serialize_char(char);
+        serialize_str(&str);
+        serialize_bytes(&[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(&'static str);
+        serialize_unit_variant(&'static str, u32, &'static str);
+    }
+
+    fn serialize_some<T: __repl_serde::Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_struct<T: __repl_serde::Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: __repl_serde::Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<Self::Ok, Self::Error> {
+        Err(__repl_serde::ser::Error::custom("not a record"))
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(__repl_serde::ser::Error::custom("not a record"))
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Ok(__ReplRow::default())
+    }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Ok(__ReplRow::default())
+    }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(__repl_serde::ser::Error::custom("not a record"))
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(__ReplRow::default())
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(__ReplRow::default())
+    }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(__repl_serde::ser::Error::custom("not a record"))
+    }
+}
+
+impl __repl_serde::ser::SerializeTuple for __ReplRow {
+    type Ok = Vec<(String, String)>;
+    type Error = __repl_serde_json::Error;
+    fn serialize_element<T: __repl_serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let column = self.fields.len().to_string();
+        self.fields.push((column, __repl_cell(value)));
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl __repl_serde::ser::SerializeTupleStruct for __ReplRow {
+    type Ok = Vec<(String, String)>;
+    type Error = __repl_serde_json::Error;
+    fn serialize_field<T: __repl_serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        __repl_serde::ser::SerializeTuple::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl __repl_serde::ser::SerializeMap for __ReplRow {
+    type Ok = Vec<(String, String)>;
+    type Error = __repl_serde_json::Error;
+    fn serialize_key<T: __repl_serde::Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.key = Some(__repl_cell(key));
+        Ok(())
+    }
+    fn serialize_value<T: __repl_serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self.key.take().unwrap_or_default();
+        self.fields.push((key, __repl_cell(value)));
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl __repl_serde::ser::SerializeStruct for __ReplRow {
+    type Ok = Vec<(String, String)>;
+    type Error = __repl_serde_json::Error;
+    fn serialize_field<T: __repl_serde::Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error> {
+        self.fields.push((key.to_string(), __repl_cell(value)));
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+"#;
+
+/// A node, parsed into its statements.
+struct ParsedNode {
+    /// The content of the node as written
+    source: String,
+    /// The content of the node, with the inner attributes blanked out
+    content: String,
+    /// The inner attributes of the node, like `#![feature(..)]`, which apply
+    /// to the whole crate
+    attributes: Vec<Range<usize>>,
+    stmts: Vec<syn::Stmt>,
+    directives: Directives,
+}
+
+/// Whether `stmt` is an item, which is put at the root of the crate so later
+/// nodes can use it. Brace delimited macro invocations are parsed as items,
+/// but only `macro_rules!` definitions are.
+fn is_item(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        syn::Stmt::Item(syn::Item::Macro(item)) => item.ident.is_some(),
+        syn::Stmt::Item(_) => true,
+        _ => false,
+    }
+}
+
+/// Copies the inner attributes of a node, which have to go at the very top of
+/// the crate.
+fn prepare_attributes(src: &mut SourceBuilder, node: usize, parsed: &ParsedNode) {
+    for range in &parsed.attributes {
+        src.push_node(node, &parsed.source, range.clone());
+        src.push("\n");
+    }
+}
+
+/// Copies the items of a node, which go at the root of the crate. With
+/// `export`, they are made visible to the crates of later nodes.
+fn prepare_items(src: &mut SourceBuilder, node: usize, parsed: &ParsedNode, export: bool) {
+    let ranges = source_map::statement_ranges(&parsed.content, &parsed.stmts);
+    for (i, stmt) in parsed.stmts.iter().enumerate() {
+        if !is_item(stmt) {
+            continue;
+        }
+        match (&ranges, stmt) {
+            (Some(ranges), syn::Stmt::Item(item)) if export => {
+                let mut start = ranges[i].start;
+                for (at, export) in source_map::exports(&parsed.content, ranges[i].clone(), item) {
+                    src.push_node(node, &parsed.content, start..at);
+                    src.push(export);
+                    start = at;
+                }
+                src.push_node(node, &parsed.content, start..ranges[i].end);
+            }
+            (Some(ranges), _) => src.push_node(node, &parsed.content, ranges[i].clone()),
+            (None, _) => src.push(&stmt.into_token_stream().to_string()),
+        }
+        src.push("\n");
+    }
+}
+
+/// Generates the code for the statements of a single node, which pushes the
+/// result of the node onto `node_results`. The statements are copied from the
+/// content of the node when they can be found in it, so the generated code
+/// maps back to it.
+fn prepare_node(src: &mut SourceBuilder, node: usize, parsed: &ParsedNode) {
+    let ranges = source_map::statement_ranges(&parsed.content, &parsed.stmts);
+    let len = parsed.stmts.len();
+    let mut did_push = false;
+    for (i, stmt) in parsed.stmts.iter().enumerate() {
+        if is_item(stmt) {
+            continue;
+        }
+        // Block-like expressions without a semicolon can appear anywhere, and
+        // are left as they are unless they are the value of the node.
+        let tail = match stmt {
+            syn::Stmt::Expr(_) => i + 1 == len,
+            _ => false,
+        };
+        let bench = tail && parsed.directives.bench.is_some();
+        if bench {
+            did_push = true;
+            src.push("#[allow(unused_parens)]\nlet __repl_output = __repl_bench(|| (");
+        } else if tail {
+            did_push = true;
+            // Matching on the value borrows it, rather than moving it out of
+            // a binding later nodes might use
+            src.push("#[allow(unused_parens)]\nlet __repl_output = match (");
+        }
+        match &ranges {
+            Some(ranges) => src.push_node(node, &parsed.content, ranges[i].clone()),
+            None => src.push(&stmt.into_token_stream().to_string()),
+        }
+        // On a line of its own, as the node might end in a comment
+        src.push("\n");
+        if bench {
+            src.push("));\nnode_results.push(__repl_output);\n");
+        } else if tail {
+            let value = match parsed.directives.format {
+                Format::Debug => "to_debugable",
+                Format::PrettyDebug => "to_pretty_debugable",
+                Format::Display => "to_displayable",
+            };
+            src.push(&format!(
+                ") {{ ref __repl_value => __repl_result((&&&&&__ReplValue(__repl_value)).{}(), __repl_type_name(__repl_value)) }};\nnode_results.push(__repl_output);\n",
+                value
+            ));
+        }
+    }
+    if !did_push {
+        src.push("node_results.push(\"null\".to_string());\n");
+    }
+}
+
+/// `POST /execute`. The execution runs on its own thread, like those of
+/// `/stream`, as it would hold up every other request of the worker.
 fn execute(
     (req, parameters): (actix_web::HttpRequest<AppState>, Json<ExecutionParameters>),
-) -> Result<Json<ExecutionResponse>, failure::Error> {
+) -> FutureResponse<Json<ExecutionResponse>> {
     let state = req.state();
-    println!("runninging {:?}", state.build_dir());
-    let execution = Execution {
-        run_dir: None,
-        parameters: parameters.into_inner(),
+    println!("runninging {:?}", state.pool.dir());
+    let (sender, receiver) = oneshot::channel();
+    let pool = state.pool.clone();
+    let sessions = state.sessions.clone();
+    let running = state.running.clone();
+    let parameters = parameters.into_inner();
+    std::thread::spawn(move || {
+        let execution = Execution {
+            run_dir: None,
+            parameters,
+            cancel: Cancel::default(),
+        };
+        let _ = sender.send(execute_in(&pool, &sessions, &running, &execution, &|_| ()));
+    });
+    Box::new(receiver.map(Json).map_err(error::ErrorInternalServerError))
+}
+
+/// Runs `execution` in its session, or in whichever build directory of the
+/// pool is free when it has none. It can be cancelled through `running`
+/// until it is done.
+fn execute_in(
+    pool: &Pool,
+    sessions: &Sessions,
+    running: &Running,
+    execution: &Execution,
+    events: &dyn Fn(ExecutionEvent),
+) -> ExecutionResponse {
+    let chosen = execution.parameters.execution.as_deref();
+    let id = running.start(&execution.cancel, chosen)?;
+    events(ExecutionEvent::Started {
+        execution: id.clone(),
+    });
+    let result = match &execution.parameters.session {
+        Some(session) => sessions.execute(pool, session, execution, events),
+        None => pool.execute(execution, events),
     };
-    let result = state.executioner.execute(&execution);
-    Ok(Json(result))
+    running.finish(&id);
+    result
 }
 
-#[allow(dead_code)]
+/// The state of a worker, which all of them share.
 struct AppState {
-    build_tmp_dir: Option<tempfile::TempDir>,
-    executioner: Executioner,
+    /// Shared with the threads running streamed executions.
+    pool: Arc<Pool>,
+    sessions: Arc<Sessions>,
+    running: Arc<Running>,
+    outputs: Arc<Mutex<Outputs>>,
 }
 
 impl AppState {
-    fn build_dir(&self) -> &std::path::Path {
-        self.build_tmp_dir.as_ref().unwrap().path()
+    fn outputs(&self) -> MutexGuard<'_, Outputs> {
+        lock(&self.outputs)
     }
 }
 
+/// Locks the pool or the outputs, even if an earlier execution panicked while
+/// holding them.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How often idle sessions are looked for, in seconds.
+const SESSION_COLLECT_INTERVAL_SECS: u64 = 60;
+
 fn main() {
-    server::new(|| {
-        let build_tmp_dir = tempfile::tempdir().expect("failed to create temp dir");
-        let executioner = Executioner::new(build_tmp_dir.path())
-            .expect("failed to create executioner");
+    let config = Config::from_env();
+    let outputs = Arc::new(Mutex::new(Outputs::new(config.output_limits)));
+    let pool = Arc::new(Pool::new(&config, &outputs).expect("failed to create build pool"));
+    let sessions_dir = config
+        .sessions_dir
+        .clone()
+        .unwrap_or_else(|| pool.dir().join("sessions"));
+    let sessions = Arc::new(
+        Sessions::open(sessions_dir, &pool, &config, &outputs).expect("failed to open sessions"),
+    );
+    if let Some(idle) = config.session_idle {
+        let sessions = sessions.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(SESSION_COLLECT_INTERVAL_SECS));
+            sessions.collect_idle(idle);
+        });
+    }
+    let running = Arc::new(Running::default());
+    server::new(move || {
         App::with_state(AppState {
-            build_tmp_dir: Some(build_tmp_dir),
-            executioner,
+            pool: pool.clone(),
+            sessions: sessions.clone(),
+            running: running.clone(),
+            outputs: outputs.clone(),
         }).configure(|app| {
             Cors::for_app(app)
-                .allowed_methods(vec!["GET", "POST"])
+                .allowed_methods(vec!["GET", "POST", "DELETE"])
                 .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
                 .allowed_header(http::header::CONTENT_TYPE)
                 .max_age(3600)
                 .resource("/execute", |r| r.with(execute))
+                .resource("/stream", |r| r.f(stream::connect))
+                .resource("/output/{handle}", |r| r.with(outputs::chunk))
+                .resource("/cancel/{execution}", |r| r.with(cancel::cancel))
+                .resource("/sessions", |r| r.method(http::Method::POST).with(sessions::create))
+                .resource("/sessions/lookup", |r| {
+                    r.method(http::Method::POST).with(sessions::lookup)
+                })
+                .resource("/sessions/{id}", |r| {
+                    r.method(http::Method::GET).with(sessions::notebook);
+                    r.method(http::Method::DELETE).with(sessions::delete);
+                })
                 .register()
         })
     })
@@ -243,3 +1532,65 @@ fn main() {
         .unwrap()
         .run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(nodes: &[&str]) -> Vec<ParsedNode> {
+        let execution = Execution {
+            run_dir: None,
+            parameters: ExecutionParameters {
+                nodes: nodes
+                    .iter()
+                    .map(|content| ExecutionNode {
+                        content: content.to_string(),
+                    })
+                    .collect(),
+                persistent: false,
+                build: BuildOptions::default(),
+                session: None,
+                execution: None,
+            },
+            cancel: Cancel::default(),
+        };
+        execution.parse_nodes().unwrap()
+    }
+
+    #[test]
+    fn inner_attributes_are_blanked_out() {
+        let parsed = parse(&["#![feature(test)]\nlet x = 1;\nx"]);
+        assert_eq!(parsed[0].attributes, vec![0..17]);
+        assert_eq!(parsed[0].content, format!("{}\nlet x = 1;\nx", " ".repeat(17)));
+        assert_eq!(parsed[0].stmts.len(), 2);
+    }
+
+    #[test]
+    fn attributes_and_items_are_hoisted() {
+        let parsed = parse(&[
+            "let x = 1;\n#![feature(test)]\nfn f() -> u8 { 2 }",
+            "#![allow(unused)]\nf() + x",
+        ]);
+        let (src, map) = prepare_src(&parsed, "marker", false);
+        assert!(src.starts_with("#![feature(test)]\n#![allow(unused)]\n"));
+        let item = src.find("fn f() -> u8 { 2 }").unwrap();
+        let main = src.find("fn main()").unwrap();
+        assert!(item < main);
+        assert!(src[main..].contains("let x = 1;"));
+        assert!(!src[main..].contains("#!["));
+
+        // The hoisted attribute still maps back to its node
+        let at = |line, column| LineColumn { line, column };
+        assert_eq!(map.lookup(at(1, 4)), Some((0, at(2, 4))));
+        assert_eq!(map.lookup(at(2, 4)), Some((1, at(1, 4))));
+    }
+
+    #[test]
+    fn exported_items_are_public() {
+        let parsed = parse(&["struct P { x: u8 }\nP { x: 1 }.x"]);
+        let mut src = SourceBuilder::new();
+        prepare_items(&mut src, 0, &parsed[0], true);
+        let (src, _) = src.finish();
+        assert_eq!(src, "pub struct P { pub x: u8 }\n");
+    }
+}
@@ -0,0 +1,295 @@
+// Limits on how much of the results and output of the nodes is sent to the
+// client.
+//
+// Longer results are cut down to a preview, and the full text is kept in
+// `Outputs` for the client to fetch the rest of from `/output/{handle}`, a
+// chunk at a time.
+
+use actix_web::{HttpRequest, Json, Path};
+use shared::*;
+use std::collections::{HashMap, VecDeque};
+
+use ids;
+use AppState;
+
+/// How much of the full texts is kept around. The oldest ones are dropped to
+/// stay below it.
+const STORE_BYTES: usize = 256 * 1024 * 1024;
+/// The length of a chunk, unless the client asks for another one.
+const CHUNK_LEN: usize = 64 * 1024;
+const MAX_CHUNK_LEN: usize = 1024 * 1024;
+
+/// The longest results and output sent to the client. `None` is unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputLimits {
+    pub result_len: Option<usize>,
+    pub stdout_len: Option<usize>,
+}
+
+/// The full texts of truncated results and output, by their handles. The
+/// handles are random, as anyone who has one can fetch the text.
+pub struct Outputs {
+    limits: OutputLimits,
+    texts: HashMap<String, String>,
+    /// The handles, oldest first
+    handles: VecDeque<String>,
+    bytes: usize,
+}
+
+impl Outputs {
+    pub fn new(limits: OutputLimits) -> Outputs {
+        Outputs {
+            limits,
+            texts: HashMap::new(),
+            handles: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Truncates a result longer than the limit. Rich results are cut down
+    /// to their text, which the client turns back into the result once it
+    /// fetched all of it, as half an image is no use.
+    pub fn result(&mut self, result: NodeResult) -> NodeResult {
+        let limit = match self.limits.result_len {
+            Some(limit) => limit,
+            None => return result,
+        };
+        match result {
+            NodeResult::String {
+                value,
+                format,
+                type_name,
+            } if value.len() > limit => {
+                self.truncate(value, limit, TruncatedResult::String { format, type_name })
+            }
+            NodeResult::Html(text) if text.len() > limit => {
+                self.truncate(text, limit, TruncatedResult::Html)
+            }
+            NodeResult::Svg(text) if text.len() > limit => {
+                self.truncate(text, limit, TruncatedResult::Svg)
+            }
+            NodeResult::Png(text) if text.len() > limit => {
+                self.truncate(text, limit, TruncatedResult::Png)
+            }
+            NodeResult::Markdown(text) if text.len() > limit => {
+                self.truncate(text, limit, TruncatedResult::Markdown)
+            }
+            NodeResult::Table { columns, rows } if table_len(&columns, &rows) > limit => {
+                let text =
+                    serde_json::to_string(&(columns, rows)).expect("failed to serialize table");
+                self.truncate(text, limit, TruncatedResult::Table)
+            }
+            result => result,
+        }
+    }
+
+    fn truncate(&mut self, text: String, limit: usize, of: TruncatedResult) -> NodeResult {
+        NodeResult::Truncated {
+            preview: preview(&text, limit).to_string(),
+            full_len: text.len(),
+            handle: self.keep(text),
+            of,
+        }
+    }
+
+    /// Truncates everything printed by a run to the limit.
+    pub fn stdout(&mut self, stdout: String) -> (String, Option<Truncated>) {
+        match self.limits.stdout_len {
+            Some(limit) if stdout.len() > limit => {
+                let preview = preview(&stdout, limit).to_string();
+                let full_len = stdout.len();
+                let truncated = Truncated {
+                    full_len,
+                    handle: self.keep(stdout),
+                };
+                (preview, Some(truncated))
+            }
+            _ => (stdout, None),
+        }
+    }
+
+    /// Truncates what a node printed, after `printed` bytes were printed by
+    /// the nodes before it, so that all of them stay within the limit
+    /// together.
+    pub fn node_stdout(&mut self, stdout: &str, printed: usize) -> (String, Option<Truncated>) {
+        match self.limits.stdout_len {
+            Some(limit) if printed + stdout.len() > limit => {
+                let preview = preview(stdout, limit.saturating_sub(printed)).to_string();
+                let truncated = Truncated {
+                    full_len: stdout.len(),
+                    handle: self.keep(stdout.to_string()),
+                };
+                (preview, Some(truncated))
+            }
+            _ => (stdout.to_string(), None),
+        }
+    }
+
+    pub fn stdout_limit(&self) -> Option<usize> {
+        self.limits.stdout_len
+    }
+
+    fn keep(&mut self, text: String) -> String {
+        let handle = ids::new_id().expect("failed to generate an output handle");
+        self.bytes += text.len();
+        self.texts.insert(handle.clone(), text);
+        self.handles.push_back(handle.clone());
+        while self.bytes > STORE_BYTES && self.handles.len() > 1 {
+            let oldest = self.handles.pop_front().unwrap();
+            let text = self.texts.remove(&oldest).unwrap();
+            self.bytes -= text.len();
+        }
+        handle
+    }
+
+    /// The text of `handle` from `offset` on, at most `len` bytes of it, or
+    /// `None` when there is no such text any more.
+    fn chunk(&self, handle: &str, offset: usize, len: usize) -> Option<Chunk> {
+        let text = self.texts.get(handle)?;
+        let start = floor_char_boundary(text, offset);
+        let mut end = floor_char_boundary(text, start.saturating_add(len));
+        // A chunk has at least one character, even if it is longer than `len`
+        if end == start && end < text.len() {
+            end += text[end..].chars().next().map_or(0, char::len_utf8);
+        }
+        Some(Chunk {
+            text: text[start..end].to_string(),
+            offset: start,
+            full_len: text.len(),
+        })
+    }
+}
+
+/// Roughly how much of the result limit a table takes up.
+fn table_len(columns: &[String], rows: &[Vec<String>]) -> usize {
+    columns
+        .iter()
+        .chain(rows.iter().flatten())
+        .map(String::len)
+        .sum()
+}
+
+/// The start of `text`, at most `len` bytes of it.
+pub fn preview(text: &str, len: usize) -> &str {
+    &text[..floor_char_boundary(text, len)]
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// `GET /output/{handle}?offset=..&len=..`
+pub fn chunk(
+    (req, handle): (HttpRequest<AppState>, Path<String>),
+) -> Result<Json<ChunkResponse>, ::failure::Error> {
+    let param = |name: &str| -> Result<Option<usize>, ::failure::Error> {
+        match req.query().get(name) {
+            Some(value) => Ok(Some(value.parse()?)),
+            None => Ok(None),
+        }
+    };
+    let offset = param("offset")?.unwrap_or(0);
+    let len = param("len")?.unwrap_or(CHUNK_LEN).min(MAX_CHUNK_LEN);
+    let outputs = req.state().outputs();
+    Ok(Json(outputs.chunk(&handle.into_inner(), offset, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(result_len: usize, stdout_len: usize) -> Outputs {
+        Outputs::new(OutputLimits {
+            result_len: Some(result_len),
+            stdout_len: Some(stdout_len),
+        })
+    }
+
+    #[test]
+    fn previews_end_on_char_boundaries() {
+        assert_eq!(preview("héllo", 2), "h");
+        assert_eq!(preview("héllo", 3), "hé");
+        assert_eq!(preview("héllo", 100), "héllo");
+        assert_eq!(preview("", 1), "");
+    }
+
+    #[test]
+    fn chunks_end_on_char_boundaries() {
+        let mut outputs = outputs(1, 1);
+        let handle = outputs.keep("aé€b".to_string());
+        let chunk = outputs.chunk(&handle, 0, 2).unwrap();
+        assert_eq!(
+            (chunk.text.as_str(), chunk.offset, chunk.full_len),
+            ("a", 0, 7)
+        );
+        // An offset inside of a character starts at the character
+        let chunk = outputs.chunk(&handle, 2, 4).unwrap();
+        assert_eq!((chunk.text.as_str(), chunk.offset), ("é", 1));
+        // A chunk too short for the next character gets it anyway
+        let chunk = outputs.chunk(&handle, 3, 1).unwrap();
+        assert_eq!((chunk.text.as_str(), chunk.offset), ("€", 3));
+        let chunk = outputs.chunk(&handle, 6, 100).unwrap();
+        assert_eq!(chunk.text, "b");
+        assert_eq!(outputs.chunk(&handle, 100, 1).unwrap().text, "");
+        assert!(outputs.chunk("0", 0, 1).is_none());
+    }
+
+    #[test]
+    fn truncated_results_can_be_restored() {
+        let mut outputs = outputs(4, 4);
+        let table = NodeResult::Table {
+            columns: vec!["name".to_string()],
+            rows: vec![vec!["value".to_string()]],
+        };
+        let truncated = outputs.result(table);
+        let (handle, of) = match &truncated {
+            NodeResult::Truncated { handle, of, .. } => (handle, of),
+            result => panic!("unexpected {:?}", result),
+        };
+        let text = outputs.chunk(handle, 0, MAX_CHUNK_LEN).unwrap().text;
+        match of.restore(text) {
+            Some(NodeResult::Table { columns, rows }) => {
+                assert_eq!(columns, ["name"]);
+                assert_eq!(rows, [["value"]]);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        match outputs.result(NodeResult::Html("<b>bold</b>".to_string())) {
+            NodeResult::Truncated {
+                preview,
+                full_len,
+                of: TruncatedResult::Html,
+                ..
+            } => {
+                assert_eq!((preview.as_str(), full_len), ("<b>b", 11));
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        match outputs.result(NodeResult::Svg("<g/>".to_string())) {
+            NodeResult::Svg(svg) => assert_eq!(svg, "<g/>"),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn node_stdouts_share_the_limit() {
+        let mut outputs = outputs(4, 4);
+        let (stdout, truncated) = outputs.node_stdout("ab", 0);
+        assert_eq!(stdout, "ab");
+        assert!(truncated.is_none());
+        let (stdout, truncated) = outputs.node_stdout("cdef", 2);
+        assert_eq!(stdout, "cd");
+        let truncated = truncated.unwrap();
+        assert_eq!(truncated.full_len, 4);
+        assert_eq!(outputs.chunk(&truncated.handle, 2, 10).unwrap().text, "ef");
+        let (stdout, truncated) = outputs.node_stdout("g", 6);
+        assert_eq!(stdout, "");
+        assert!(truncated.is_some());
+    }
+}
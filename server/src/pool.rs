@@ -0,0 +1,201 @@
+// The build directories shared by all the workers of the server.
+//
+// Every execution gets a build directory of its own out of the pool, so
+// concurrent executions never write over each other's sources. The
+// directories share a single target directory, where the dependencies are
+// only built once. At most `Config::concurrency` executions run at the same
+// time, counting those in build directories of their own, and the ones
+// arriving while all of the slots are taken wait in a queue, in the order
+// they arrived.
+//
+// Persistent executions need the same kernel every time, which only a session
+// has, so the pool turns them away.
+
+use shared::*;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use cancel::{Cancel, CANCEL_POLL};
+use outputs::Outputs;
+use {lock, Config, Execution, Executioner};
+
+pub struct Pool {
+    dir: tempfile::TempDir,
+    target_dir: PathBuf,
+    /// How many executions run at the same time.
+    slots: usize,
+    state: Mutex<PoolState>,
+    /// Signalled whenever a slot is freed, or the queue moves.
+    changed: Condvar,
+}
+
+struct PoolState {
+    /// There is one for every free slot, at least.
+    idle: Vec<Executioner>,
+    running: usize,
+    /// The tickets of the waiting executions, the next one in front.
+    queue: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+impl Pool {
+    /// Sets up `config.concurrency` build directories, which is how many
+    /// executions run at the same time.
+    pub fn new(config: &Config, outputs: &Arc<Mutex<Outputs>>) -> Result<Pool, failure::Error> {
+        let dir = tempfile::tempdir()?;
+        let target_dir = dir.path().canonicalize()?.join("target");
+        std::fs::create_dir_all(&target_dir)?;
+        let slots = config.concurrency.max(1);
+        let idle = (0..slots)
+            .map(|i| {
+                Executioner::new(
+                    dir.path().join(format!("build_{}", i)),
+                    target_dir.clone(),
+                    format!("runtree_{}", i),
+                    config.clone(),
+                    outputs.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Pool {
+            dir,
+            target_dir,
+            slots,
+            state: Mutex::new(PoolState {
+                idle,
+                running: 0,
+                queue: VecDeque::new(),
+                next_ticket: 0,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Where all executioners put what cargo builds.
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    /// Runs `execution` as soon as a build directory is free. While it
+    /// waits, `events` is told how many executions are ahead of it whenever
+    /// that changes.
+    pub fn execute(
+        &self,
+        execution: &Execution,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> ExecutionResponse {
+        if execution.parameters.persistent {
+            return Err(XXError::PersistentWithoutSession);
+        }
+        self.execute_in(None, execution, events)
+    }
+
+    /// Like `execute`, but runs in the build directory of `executioner`
+    /// when given one. It still waits for a slot, so it counts towards the
+    /// executions running at the same time, but leaves the build directories
+    /// of the pool to others.
+    pub fn execute_in(
+        &self,
+        executioner: Option<&Executioner>,
+        execution: &Execution,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> ExecutionResponse {
+        let (lease, queue_position) =
+            self.acquire(executioner.is_none(), &execution.cancel, events)?;
+        let executioner = executioner.unwrap_or_else(|| lease.executioner());
+        let result = executioner.execute(execution, events);
+        result.map(|result| ExecutionResult {
+            queue_position,
+            ..result
+        })
+    }
+
+    /// Takes a slot, and with `executioner` an executioner out of the pool,
+    /// along with the position the execution got in the queue, if it had to
+    /// wait. Gives up on waiting once `cancel` is cancelled.
+    fn acquire(
+        &self,
+        executioner: bool,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<(Lease<'_>, Option<usize>), XXError> {
+        let mut state = lock(&self.state);
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push_back(ticket);
+
+        let mut first_position = None;
+        let mut reported = None;
+        loop {
+            let position = state
+                .queue
+                .iter()
+                .position(|&waiting| waiting == ticket)
+                .expect("ticket left the queue");
+            if position == 0 && state.running < self.slots {
+                state.running += 1;
+                state.queue.pop_front();
+                let executioner = if executioner {
+                    Some(state.idle.pop().expect("no executioner for a free slot"))
+                } else {
+                    None
+                };
+                // The next one in the queue might get a slot too
+                self.changed.notify_all();
+                let lease = Lease {
+                    pool: self,
+                    executioner,
+                };
+                return Ok((lease, first_position));
+            }
+            if cancel.is_cancelled() {
+                state.queue.remove(position);
+                self.changed.notify_all();
+                return Err(XXError::Cancelled {
+                    partial_stdout: String::new(),
+                });
+            }
+            first_position.get_or_insert(position);
+            if reported != Some(position) {
+                reported = Some(position);
+                events(ExecutionEvent::Queued { position });
+            }
+            // Woken up now and then to notice cancellation
+            state = self
+                .changed
+                .wait_timeout(state, CANCEL_POLL)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+/// A slot, and maybe an executioner taken out of the pool, which are given
+/// back when dropped, even if the execution panicked.
+struct Lease<'a> {
+    pool: &'a Pool,
+    executioner: Option<Executioner>,
+}
+
+impl<'a> Lease<'a> {
+    fn executioner(&self) -> &Executioner {
+        self.executioner.as_ref().unwrap()
+    }
+}
+
+impl<'a> Drop for Lease<'a> {
+    fn drop(&mut self) {
+        let mut state = lock(&self.pool.state);
+        state.running -= 1;
+        if let Some(executioner) = self.executioner.take() {
+            state.idle.push(executioner);
+        }
+        self.pool.changed.notify_all();
+    }
+}
+
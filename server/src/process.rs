@@ -0,0 +1,243 @@
+use shared::*;
+
+use cancel::Cancel;
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long to wait between checks on a running child, at most. The checks
+/// start out more often, so short runs aren't held up.
+const MAX_POLL_MS: u64 = 10;
+
+/// A child killed before it exited on its own.
+pub struct Overrun {
+    pub elapsed: Duration,
+    pub stdout: String,
+    /// Killed for being cancelled, rather than running out of time
+    pub cancelled: bool,
+}
+
+impl Overrun {
+    pub fn into_error(self, phase: Phase) -> XXError {
+        if self.cancelled {
+            return XXError::Cancelled {
+                partial_stdout: self.stdout,
+            };
+        }
+        XXError::Timeout {
+            phase,
+            elapsed_ms: millis(self.elapsed),
+            partial_stdout: self.stdout,
+        }
+    }
+}
+
+pub fn millis(t: Duration) -> u32 {
+    (t.as_secs() * 1000 + u64::from(t.subsec_millis())) as u32
+}
+
+pub fn nanos(t: Duration) -> u64 {
+    t.as_secs() * 1_000_000_000 + u64::from(t.subsec_nanos())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pipe {
+    Stdout,
+    Stderr,
+}
+
+/// Like `Command::output`, but kills the child if it is still running after
+/// `timeout`, or once `cancel` is cancelled. Every line the child writes is
+/// handed to `on_line` as soon as it is complete.
+///
+/// The child runs in a process group of its own, and the whole group is
+/// killed, so nothing it started outlives it or keeps its pipes open.
+pub fn output(
+    command: &mut Command,
+    timeout: Duration,
+    cancel: &Cancel,
+    on_line: &mut dyn FnMut(Pipe, &str),
+) -> Result<Output, Overrun> {
+    output_timed(command, timeout, cancel, on_line).map(|(output, _)| output)
+}
+
+/// Like `output`, but also returns the CPU time, user and system, used by the
+/// child.
+// The child is reaped by `try_wait`, through `wait4`
+#[allow(clippy::zombie_processes)]
+pub fn output_timed(
+    command: &mut Command,
+    timeout: Duration,
+    cancel: &Cancel,
+    on_line: &mut dyn FnMut(Pipe, &str),
+) -> Result<(Output, Duration), Overrun> {
+    let start = Instant::now();
+    let mut child = own_process_group(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn");
+
+    let (stdout, stdout_reader) = read_in_background(child.stdout.take().unwrap());
+    let (stderr, stderr_reader) = read_in_background(child.stderr.take().unwrap());
+    let mut stdout_lines = Lines::new(Pipe::Stdout, stdout.clone());
+    let mut stderr_lines = Lines::new(Pipe::Stderr, stderr.clone());
+
+    let mut poll = Duration::from_millis(1);
+    let (status, cpu_time) = loop {
+        stdout_lines.forward(on_line, false);
+        stderr_lines.forward(on_line, false);
+        match try_wait(&child) {
+            Ok(Some(exited)) => break exited,
+            Ok(None) => {}
+            Err(error) => {
+                kill_group(&child);
+                panic!("failed to wait for child: {}", error);
+            }
+        }
+        let cancelled = cancel.is_cancelled();
+        if cancelled || start.elapsed() >= timeout {
+            kill_group(&child);
+            let _ = child.wait();
+            // With the whole group gone, the pipes are closed
+            let _ = stdout_reader.join();
+            let _ = stderr_reader.join();
+            let stdout = String::from_utf8_lossy(&stdout.lock().unwrap()).into_owned();
+            return Err(Overrun {
+                elapsed: start.elapsed(),
+                stdout,
+                cancelled,
+            });
+        }
+        std::thread::sleep(poll);
+        poll = (poll * 2).min(Duration::from_millis(MAX_POLL_MS));
+    };
+
+    // Anything the child left running in the background could keep the pipes
+    // open, and the readers from ever finishing
+    kill_group(&child);
+    let _ = stdout_reader.join();
+    let _ = stderr_reader.join();
+    stdout_lines.forward(on_line, true);
+    stderr_lines.forward(on_line, true);
+
+    let stdout = std::mem::take(&mut *stdout.lock().unwrap());
+    let stderr = std::mem::take(&mut *stderr.lock().unwrap());
+    Ok((
+        Output {
+            status,
+            stdout,
+            stderr,
+        },
+        cpu_time,
+    ))
+}
+
+/// Has the child spawned by `command` start a process group of its own, so
+/// it can be killed along with everything it started, by `kill_group`.
+pub fn own_process_group(command: &mut Command) -> &mut Command {
+    // `setpgid` is async-signal-safe, so it is fine between fork and exec
+    #[allow(deprecated)]
+    command.before_exec(|| {
+        if unsafe { libc::setpgid(0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Kills the process group of `child`, which was spawned by a command passed
+/// to `own_process_group`.
+pub fn kill_group(child: &Child) {
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+/// Like `Child::try_wait`, but also returns the CPU time the child used.
+fn try_wait(child: &Child) -> std::io::Result<Option<(ExitStatus, Duration)>> {
+    let mut status = 0;
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    loop {
+        let pid = unsafe {
+            libc::wait4(
+                child.id() as libc::pid_t,
+                &mut status,
+                libc::WNOHANG,
+                &mut usage,
+            )
+        };
+        if pid == 0 {
+            return Ok(None);
+        }
+        if pid > 0 {
+            break;
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    let time = |time: libc::timeval| {
+        Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+    };
+    Ok(Some((
+        ExitStatus::from_raw(status),
+        time(usage.ru_utime) + time(usage.ru_stime),
+    )))
+}
+
+/// The part of a pipe's output which has been handed out line by line.
+struct Lines {
+    pipe: Pipe,
+    buf: Arc<Mutex<Vec<u8>>>,
+    forwarded: usize,
+}
+
+impl Lines {
+    fn new(pipe: Pipe, buf: Arc<Mutex<Vec<u8>>>) -> Lines {
+        Lines {
+            pipe,
+            buf,
+            forwarded: 0,
+        }
+    }
+
+    /// Hands out the complete lines written since last time, or everything
+    /// that is left once the pipe is `closed`.
+    fn forward(&mut self, on_line: &mut dyn FnMut(Pipe, &str), closed: bool) {
+        let buf = self.buf.lock().unwrap();
+        let pending = &buf[self.forwarded..];
+        let end = if closed {
+            pending.len()
+        } else {
+            pending
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1)
+        };
+        for line in String::from_utf8_lossy(&pending[..end]).lines() {
+            on_line(self.pipe, line);
+        }
+        self.forwarded += end;
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(
+    mut reader: R,
+) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<()>) {
+    let buf = Arc::new(Mutex::new(vec![]));
+    let sink = buf.clone();
+    let handle = std::thread::spawn(move || {
+        let mut chunk = [0; 1024];
+        while let Ok(n) = reader.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            sink.lock().unwrap().extend_from_slice(&chunk[..n]);
+        }
+    });
+    (buf, handle)
+}
@@ -0,0 +1,341 @@
+use shared::*;
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::time::Duration;
+
+/// How far below the CPU limit the CPU time of a killed program may be, and
+/// still count as having run out of it. The kernel enforces the limit on
+/// its own accounting, which runs slightly ahead of what `wait4` reports.
+const CPU_TIME_SLACK_MS: u64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    pub cpu_seconds: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    pub file_size_bytes: Option<u64>,
+    pub processes: Option<u64>,
+    pub isolate_network: bool,
+    pub seccomp: bool,
+}
+
+impl Sandbox {
+    /// The kernel lives across many runs, so CPU time would add up between
+    /// nodes. Its nodes are still bounded by the run timeout.
+    pub fn without_cpu_limit(&self) -> Sandbox {
+        Sandbox {
+            cpu_seconds: None,
+            ..self.clone()
+        }
+    }
+
+    /// Restricts the program spawned by `command`, and runs it in
+    /// `scratch_dir`. The file system is not confined: the program can read
+    /// and write anything the server can, including the build directories of
+    /// other executions and sessions.
+    pub fn apply<'a>(&self, command: &'a mut Command, scratch_dir: &Path) -> &'a mut Command {
+        command
+            .current_dir(scratch_dir)
+            .env("HOME", scratch_dir)
+            .env("TMPDIR", scratch_dir);
+
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_AS, self.memory_bytes),
+            (libc::RLIMIT_FSIZE, self.file_size_bytes),
+            (libc::RLIMIT_NPROC, self.processes),
+        ];
+        let isolate_network = self.isolate_network;
+        let filter = if self.seccomp {
+            Some(platform::seccomp_filter())
+        } else {
+            None
+        };
+
+        // Only async-signal-safe calls are allowed between fork and exec, so
+        // everything allocating has been done up front.
+        #[allow(deprecated)]
+        command.before_exec(move || unsafe {
+            for &(resource, limit) in &limits {
+                if let Some(limit) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            platform::isolate(isolate_network, filter.as_ref())
+        })
+    }
+
+    /// Figures out whether a failed program was stopped by one of the limits.
+    /// `cpu_time` is the CPU time the program used, when it was measured.
+    pub fn violation(
+        &self,
+        status: ExitStatus,
+        cpu_time: Option<Duration>,
+        stderr: &str,
+    ) -> Option<Limit> {
+        if status.success() {
+            return None;
+        }
+        let out_of_cpu = match (self.cpu_seconds, cpu_time) {
+            (Some(limit), Some(cpu_time)) => {
+                cpu_time + Duration::from_millis(CPU_TIME_SLACK_MS) >= Duration::from_secs(limit)
+            }
+            _ => false,
+        };
+        match status.signal() {
+            Some(libc::SIGXCPU) if self.cpu_seconds.is_some() => Some(Limit::Cpu),
+            // With equal soft and hard limits the program is killed outright,
+            // which looks like any other SIGKILL but for the time it took
+            Some(libc::SIGKILL) if out_of_cpu => Some(Limit::Cpu),
+            Some(libc::SIGXFSZ) if self.file_size_bytes.is_some() => Some(Limit::FileSize),
+            Some(libc::SIGSYS) if self.seccomp => Some(Limit::Syscall),
+            Some(libc::SIGABRT)
+                if self.memory_bytes.is_some() && stderr.contains("memory allocation of") =>
+            {
+                Some(Limit::Memory)
+            }
+            _ if self.processes.is_some()
+                && stderr.contains("Resource temporarily unavailable") =>
+            {
+                Some(Limit::Processes)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+
+    // From `linux/filter.h` and `linux/seccomp.h`, which the libc crate
+    // doesn't cover in the version we are on.
+    #[repr(C)]
+    pub struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    const BPF_LD: u16 = 0x00;
+    const BPF_JMP: u16 = 0x05;
+    const BPF_RET: u16 = 0x06;
+    const BPF_W: u16 = 0x00;
+    const BPF_ABS: u16 = 0x20;
+    const BPF_JEQ: u16 = 0x10;
+    const BPF_JGE: u16 = 0x30;
+    const BPF_K: u16 = 0x00;
+
+    const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    /// Set in the numbers of x32 syscalls, which x86_64 kernels might accept
+    /// under the same `AUDIT_ARCH`. No other architecture has syscall numbers
+    /// this high.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    pub type Filter = Vec<SockFilter>;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    /// Syscalls a snippet has no business making.
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_socket,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        // Rings can open sockets and files without making the syscalls
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+    ];
+
+    pub fn seccomp_filter() -> Filter {
+        fn stmt(code: u16, k: u32) -> SockFilter {
+            jump(code, k, 0, 0)
+        }
+        fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+            SockFilter { code, jt, jf, k }
+        }
+
+        let arch = AUDIT_ARCH.expect("seccomp is not supported on this architecture");
+
+        // Offsets into `struct seccomp_data`
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+
+        let mut filter = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, ARCH),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, NR),
+            jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        ];
+        for &syscall in DENIED_SYSCALLS {
+            filter.push(jump(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                syscall as u32,
+                0,
+                1,
+            ));
+            filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+        }
+        filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        filter
+    }
+
+    /// Moves the calling process into its own network namespace and installs
+    /// the seccomp filter. Called between fork and exec.
+    pub unsafe fn isolate(network: bool, filter: Option<&Filter>) -> io::Result<()> {
+        if network && libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(filter) = filter {
+            let program = SockFprog {
+                len: filter.len() as u16,
+                filter: filter[..].as_ptr(),
+            };
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    SECCOMP_MODE_FILTER,
+                    &program as *const SockFprog,
+                ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+
+    pub type Filter = ();
+
+    pub fn seccomp_filter() -> Filter {
+        panic!("seccomp is only supported on Linux")
+    }
+
+    pub unsafe fn isolate(network: bool, _filter: Option<&Filter>) -> io::Result<()> {
+        if network {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "network isolation is only supported on Linux",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> Sandbox {
+        Sandbox {
+            cpu_seconds: Some(1),
+            memory_bytes: Some(1 << 30),
+            file_size_bytes: Some(1 << 20),
+            processes: None,
+            isolate_network: false,
+            seccomp: true,
+        }
+    }
+
+    fn signalled(signal: libc::c_int) -> ExitStatus {
+        ExitStatus::from_raw(signal)
+    }
+
+    #[test]
+    fn successful_runs_violate_nothing() {
+        assert_eq!(sandbox().violation(ExitStatus::from_raw(0), None, ""), None);
+    }
+
+    #[test]
+    fn sigkill_is_only_the_cpu_limit_once_reached() {
+        let sandbox = sandbox();
+        let kill = signalled(libc::SIGKILL);
+        assert_eq!(
+            sandbox.violation(kill, Some(Duration::from_millis(998)), ""),
+            Some(Limit::Cpu)
+        );
+        assert_eq!(sandbox.violation(kill, Some(Duration::from_millis(200)), ""), None);
+        assert_eq!(sandbox.violation(kill, None, ""), None);
+    }
+
+    #[test]
+    fn signals_map_to_their_limits() {
+        let sandbox = sandbox();
+        assert_eq!(sandbox.violation(signalled(libc::SIGXCPU), None, ""), Some(Limit::Cpu));
+        assert_eq!(
+            sandbox.violation(signalled(libc::SIGXFSZ), None, ""),
+            Some(Limit::FileSize)
+        );
+        assert_eq!(sandbox.violation(signalled(libc::SIGSYS), None, ""), Some(Limit::Syscall));
+        assert_eq!(
+            sandbox.violation(
+                signalled(libc::SIGABRT),
+                None,
+                "memory allocation of 1024 bytes failed"
+            ),
+            Some(Limit::Memory)
+        );
+        assert_eq!(sandbox.violation(signalled(libc::SIGABRT), None, "panicked"), None);
+    }
+
+    #[test]
+    fn limits_which_are_off_are_never_blamed() {
+        let sandbox = Sandbox {
+            seccomp: false,
+            ..sandbox()
+        };
+        assert_eq!(sandbox.violation(signalled(libc::SIGSYS), None, ""), None);
+        assert_eq!(
+            sandbox.violation(ExitStatus::from_raw(1 << 8), None, "Resource temporarily unavailable"),
+            None
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// Named sessions, each with a build directory, dependencies and notebook of
+// their own, so that several people can use the server without running in
+// each other's directories or persistent state.
+//
+// Every session is a directory in `Sessions::dir`, holding its build
+// directory and its notebook in `session.json`. Sessions are loaded from
+// there again when the server starts, and deleted once they have been idle
+// for too long.
+
+use actix_web::{HttpRequest, Json, Path};
+use shared::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use cancel::{Cancel, CANCEL_POLL};
+use ids::{is_id, new_id};
+use outputs::Outputs;
+use pool::Pool;
+use {lock, AppState, Config, Execution, Executioner};
+
+/// What is kept of a session in its `session.json`.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    name: String,
+    nodes: Vec<ExecutionNode>,
+}
+
+struct Session {
+    id: String,
+    dir: PathBuf,
+    name: String,
+    notebook: Mutex<Notebook>,
+    /// Held for as long as an execution runs in the session.
+    executioner: Mutex<Executioner>,
+    /// Set when the session is deleted, so its directory is removed once the
+    /// last execution in it is done.
+    deleted: AtomicBool,
+}
+
+struct Notebook {
+    nodes: Vec<ExecutionNode>,
+    last_used: Instant,
+}
+
+impl Session {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            idle_secs: lock(&self.notebook).last_used.elapsed().as_secs(),
+        }
+    }
+
+    fn touch(&self) {
+        lock(&self.notebook).last_used = Instant::now();
+    }
+
+    /// Keeps `nodes` as the notebook of the session, in memory and on disk.
+    fn save(&self, nodes: &[ExecutionNode]) -> Result<(), failure::Error> {
+        let mut notebook = lock(&self.notebook);
+        notebook.nodes = nodes.to_vec();
+        let saved = Saved {
+            name: self.name.clone(),
+            nodes: notebook.nodes.clone(),
+        };
+        std::fs::write(self.dir.join("session.json"), serde_json::to_string(&saved)?)?;
+        Ok(())
+    }
+
+    /// Waits for the execution running in the session to be done, telling
+    /// `events` that it is next in line. Gives up once `cancel` is cancelled.
+    fn executioner(
+        &self,
+        cancel: &Cancel,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> Result<MutexGuard<'_, Executioner>, XXError> {
+        let mut queued = false;
+        loop {
+            match self.executioner.try_lock() {
+                Ok(executioner) => return Ok(executioner),
+                Err(TryLockError::Poisoned(poisoned)) => return Ok(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            if cancel.is_cancelled() {
+                return Err(XXError::Cancelled {
+                    partial_stdout: String::new(),
+                });
+            }
+            if !queued {
+                queued = true;
+                events(ExecutionEvent::Queued { position: 0 });
+            }
+            std::thread::sleep(CANCEL_POLL);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if *self.deleted.get_mut() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+pub struct Sessions {
+    dir: PathBuf,
+    /// Shared with the pool, so sessions reuse the dependencies it built.
+    target_dir: PathBuf,
+    config: Config,
+    outputs: Arc<Mutex<Outputs>>,
+    sessions: Mutex<BTreeMap<String, Arc<Session>>>,
+}
+
+impl Sessions {
+    /// Loads the sessions saved in `dir`.
+    pub fn open(
+        dir: PathBuf,
+        pool: &Pool,
+        config: &Config,
+        outputs: &Arc<Mutex<Outputs>>,
+    ) -> Result<Sessions, failure::Error> {
+        std::fs::create_dir_all(&dir)?;
+        let sessions = Sessions {
+            dir,
+            target_dir: pool.target_dir().to_path_buf(),
+            config: config.clone(),
+            outputs: outputs.clone(),
+            sessions: Mutex::new(BTreeMap::new()),
+        };
+        for entry in std::fs::read_dir(&sessions.dir)? {
+            let id = entry?.file_name().to_string_lossy().into_owned();
+            if !is_id(&id) {
+                continue;
+            }
+            let saved = std::fs::read_to_string(sessions.dir.join(&id).join("session.json"))
+                .map_err(failure::Error::from)
+                .and_then(|saved| Ok(serde_json::from_str::<Saved>(&saved)?));
+            match saved {
+                Ok(saved) => {
+                    sessions.insert(id, saved)?;
+                }
+                Err(error) => eprintln!("skipping session {}: {}", id, error),
+            }
+        }
+        Ok(sessions)
+    }
+
+    fn insert(&self, id: String, saved: Saved) -> Result<SessionInfo, failure::Error> {
+        let dir = self.dir.join(&id);
+        let executioner = Executioner::new(
+            dir.join("build"),
+            self.target_dir.clone(),
+            format!("session_{}", id),
+            self.config.clone(),
+            self.outputs.clone(),
+        )?;
+        let session = Session {
+            id: id.clone(),
+            dir,
+            name: saved.name,
+            notebook: Mutex::new(Notebook {
+                nodes: vec![],
+                last_used: Instant::now(),
+            }),
+            executioner: Mutex::new(executioner),
+            deleted: AtomicBool::new(false),
+        };
+        session.save(&saved.nodes)?;
+        let info = session.info();
+        lock(&self.sessions).insert(id, Arc::new(session));
+        Ok(info)
+    }
+
+    fn create(&self, name: String) -> Result<SessionInfo, failure::Error> {
+        let id = new_id()?;
+        self.insert(id, Saved { name, nodes: vec![] })
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Session>> {
+        lock(&self.sessions).get(id).cloned()
+    }
+
+    /// The sessions out of `ids` which exist.
+    fn lookup(&self, ids: &[String]) -> Vec<SessionInfo> {
+        let sessions = lock(&self.sessions);
+        let mut sessions = ids
+            .iter()
+            .filter_map(|id| sessions.get(id))
+            .map(|session| session.info())
+            .collect::<Vec<_>>();
+        sessions.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+        sessions.dedup_by(|a, b| a.id == b.id);
+        sessions
+    }
+
+    fn delete(&self, id: &str) -> bool {
+        match lock(&self.sessions).remove(id) {
+            Some(session) => {
+                session.deleted.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs `execution` in the session `id` once the execution running in it
+    /// is done and there is a slot for it in `pool`, and keeps its nodes as
+    /// the notebook of the session.
+    pub fn execute(
+        &self,
+        pool: &Pool,
+        id: &str,
+        execution: &Execution,
+        events: &dyn Fn(ExecutionEvent),
+    ) -> ExecutionResponse {
+        let session = self.get(id).ok_or_else(|| XXError::UnknownSession {
+            session: id.to_string(),
+        })?;
+        session.touch();
+        if let Err(error) = session.save(&execution.parameters.nodes) {
+            eprintln!("failed to save session {}: {}", id, error);
+        }
+        let executioner = session.executioner(&execution.cancel, events)?;
+        let result = pool.execute_in(Some(&executioner), execution, events);
+        session.touch();
+        result
+    }
+
+    /// Deletes the sessions which have not run anything for `idle`, and
+    /// aren't running anything now.
+    pub fn collect_idle(&self, idle: Duration) {
+        let mut sessions = lock(&self.sessions);
+        let expired = sessions
+            .values()
+            .filter(|session| lock(&session.notebook).last_used.elapsed() > idle)
+            .filter(|session| {
+                !matches!(session.executioner.try_lock(), Err(TryLockError::WouldBlock))
+            })
+            .map(|session| session.id.clone())
+            .collect::<Vec<_>>();
+        for id in expired {
+            if let Some(session) = sessions.remove(&id) {
+                eprintln!("deleting idle session {}", id);
+                session.deleted.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// `POST /sessions/lookup`, with the ids of the sessions to list. The ids
+/// are all it takes to use a session, so the sessions are never listed to
+/// someone who doesn't know them already.
+pub fn lookup(
+    (req, ids): (HttpRequest<AppState>, Json<Vec<String>>),
+) -> Json<Vec<SessionInfo>> {
+    Json(req.state().sessions.lookup(&ids.into_inner()))
+}
+
+/// `POST /sessions`
+pub fn create(
+    (req, new): (HttpRequest<AppState>, Json<NewSession>),
+) -> Result<Json<SessionInfo>, failure::Error> {
+    Ok(Json(req.state().sessions.create(new.into_inner().name)?))
+}
+
+/// `GET /sessions/{id}`
+pub fn notebook((req, id): (HttpRequest<AppState>, Path<String>)) -> Json<SessionResponse> {
+    let session = req.state().sessions.get(&id.into_inner());
+    Json(session.map(|session| SessionNotebook {
+        session: session.info(),
+        nodes: lock(&session.notebook).nodes.clone(),
+    }))
+}
+
+/// `DELETE /sessions/{id}`
+pub fn delete((req, id): (HttpRequest<AppState>, Path<String>)) -> Json<bool> {
+    Json(req.state().sessions.delete(&id.into_inner()))
+}
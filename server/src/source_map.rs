@@ -0,0 +1,676 @@
+// Generated sources, and where their code came from.
+//
+// The statements of a node are copied verbatim into the generated sources
+// whenever they can be found in the node, so positions reported by the
+// compiler can be mapped back to a line and column in the node.
+
+use quote::ToTokens;
+use shared::*;
+use std::ops::Range;
+
+/// How many prefixes of the statement going wrong are tried at most, as
+/// every one of them is parsed in full.
+const MAX_PARSE_ATTEMPTS: usize = 64;
+
+/// A piece of code copied from a node.
+struct Mapping {
+    node: usize,
+    /// Where the code starts in the node
+    original: LineColumn,
+    /// Where the code starts and ends in the generated source
+    start: LineColumn,
+    end: LineColumn,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Finds the node and the position in it, which ended up at `position`
+    /// in the generated source.
+    pub fn lookup(&self, position: LineColumn) -> Option<(usize, LineColumn)> {
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.start <= position && position <= mapping.end)?;
+        let original = if position.line == mapping.start.line {
+            LineColumn {
+                line: mapping.original.line,
+                column: mapping.original.column + position.column - mapping.start.column,
+            }
+        } else {
+            LineColumn {
+                line: mapping.original.line + position.line - mapping.start.line,
+                column: position.column,
+            }
+        };
+        Some((mapping.node, original))
+    }
+
+    /// Maps a span of the generated source, if it lies within a single node.
+    pub fn lookup_span(&self, start: LineColumn, end: LineColumn) -> Option<(usize, Span)> {
+        let (node, start) = self.lookup(start)?;
+        let (end_node, end) = self.lookup(end)?;
+        if node == end_node {
+            Some((node, Span { start, end }))
+        } else {
+            None
+        }
+    }
+}
+
+/// Builds a generated source, keeping track of the code copied from nodes.
+pub struct SourceBuilder {
+    src: String,
+    position: LineColumn,
+    map: SourceMap,
+}
+
+impl SourceBuilder {
+    pub fn new() -> SourceBuilder {
+        SourceBuilder {
+            src: String::new(),
+            position: LineColumn { line: 1, column: 1 },
+            map: SourceMap::default(),
+        }
+    }
+
+    pub fn push(&mut self, code: &str) {
+        for c in code.chars() {
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.column = 1;
+            } else {
+                self.position.column += 1;
+            }
+        }
+        self.src.push_str(code);
+    }
+
+    /// Copies `range` of the `content` of a node.
+    pub fn push_node(&mut self, node: usize, content: &str, range: Range<usize>) {
+        let start = self.position;
+        self.push(&content[range.clone()]);
+        self.map.mappings.push(Mapping {
+            node,
+            original: position(content, range.start),
+            start,
+            end: self.position,
+        });
+    }
+
+    pub fn finish(self) -> (String, SourceMap) {
+        (self.src, self.map)
+    }
+}
+
+/// The line and column of the byte `offset` in `src`.
+pub fn position(src: &str, offset: usize) -> LineColumn {
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    LineColumn {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+/// Finds the text of every statement of a node in its `content`. Each range
+/// starts where the previous one ended, so together they cover the node.
+pub fn statement_ranges(content: &str, stmts: &[syn::Stmt]) -> Option<Vec<Range<usize>>> {
+    let candidates = statement_ends(content);
+    let mut ranges = vec![];
+    let mut start = 0;
+    for stmt in stmts {
+        let expected = stmt.into_token_stream().to_string();
+        let end = candidates
+            .iter()
+            .cloned()
+            .filter(|&end| end > start)
+            .find(|&end| {
+                syn::parse_str::<syn::Block>(&format!("{{{}\n}}", &content[start..end]))
+                    .map(|block| {
+                        block.stmts.len() == 1
+                            && block.stmts[0].clone().into_token_stream().to_string() == expected
+                    })
+                    .unwrap_or(false)
+            })?;
+        ranges.push(start..end);
+        start = end;
+    }
+    if let Some(last) = ranges.last_mut() {
+        last.end = content.len();
+    }
+    Some(ranges)
+}
+
+/// Finds what has to be added to `item`, found in `range` of `content`, for
+/// other crates to see it: `pub` in front of the item, its fields and the
+/// associated items of an inherent impl, and `#[macro_export]` in front of a
+/// `macro_rules!` definition. Parts with a visibility of their own are left
+/// alone.
+pub fn exports(content: &str, range: Range<usize>, item: &syn::Item) -> Vec<(usize, &'static str)> {
+    let src = &content[range.clone()];
+    let tokens = tokens(src);
+    let text = |i: usize| tokens.get(i).map(|token: &Token| &src[token.range.clone()]);
+    let skip_attributes = |mut i: usize| {
+        while text(i) == Some("#") && text(i + 1) == Some("[") {
+            i = matching(&tokens, i + 1) + 1;
+        }
+        i
+    };
+    let private = |vis: &syn::Visibility| matches!(vis, syn::Visibility::Inherited);
+
+    let start = skip_attributes(0);
+    if start >= tokens.len() {
+        return vec![];
+    }
+    // The visibility of the item, and of its members in the brackets opened
+    // by `open` and split up by `separator`
+    let (vis, members, open, separator) = match item {
+        syn::Item::Struct(item) => match &item.fields {
+            syn::Fields::Named(fields) => {
+                let members = fields.named.iter().map(|field| &field.vis).collect();
+                (Some(&item.vis), members, "{", ",")
+            }
+            syn::Fields::Unnamed(fields) => {
+                let members = fields.unnamed.iter().map(|field| &field.vis).collect();
+                (Some(&item.vis), members, "(", ",")
+            }
+            syn::Fields::Unit => (Some(&item.vis), vec![], "", ""),
+        },
+        syn::Item::Union(item) => {
+            let members = item.fields.named.iter().map(|field| &field.vis).collect();
+            (Some(&item.vis), members, "{", ",")
+        }
+        syn::Item::Impl(item) if item.trait_.is_none() => {
+            let members = item
+                .items
+                .iter()
+                .map(|member| match member {
+                    syn::ImplItem::Const(member) => Some(&member.vis),
+                    syn::ImplItem::Method(member) => Some(&member.vis),
+                    syn::ImplItem::Type(member) => Some(&member.vis),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            match members {
+                Some(members) => (None, members, "{", ";"),
+                None => return vec![],
+            }
+        }
+        syn::Item::Use(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Static(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Const(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Fn(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Mod(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Type(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Enum(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Trait(item) => (Some(&item.vis), vec![], "", ""),
+        syn::Item::Macro(item) if item.ident.is_some() => {
+            return vec![(range.start + tokens[start].range.start, "#[macro_export] ")];
+        }
+        _ => return vec![],
+    };
+
+    let mut exports = vec![];
+    if vis.is_some_and(private) {
+        exports.push((range.start + tokens[start].range.start, "pub "));
+    }
+    if members.is_empty() {
+        return exports;
+    }
+
+    // Angle brackets aren't paired up by the tokens, so they are counted
+    // along the way, leaving out the `>` of `->`
+    let angle = |i: usize, depth: &mut usize| match text(i) {
+        Some("<") => *depth += 1,
+        Some(">") if i > 0 && text(i - 1) != Some("-") => *depth = depth.saturating_sub(1),
+        _ => {}
+    };
+    let mut depth = 0;
+    let mut i = start;
+    let body = loop {
+        match tokens.get(i) {
+            None => return exports,
+            Some(token) => match token.kind {
+                TokenKind::Open(_) if depth == 0 && text(i) == Some(open) => break i,
+                TokenKind::Open(_) => i = matching(&tokens, i) + 1,
+                _ => {
+                    angle(i, &mut depth);
+                    i += 1;
+                }
+            },
+        }
+    };
+
+    // Each member runs up to the next separator, while an associated item
+    // also ends with its block
+    let close = matching(&tokens, body);
+    let mut starts = vec![];
+    let mut i = body + 1;
+    while i < close {
+        let start = skip_attributes(i);
+        if start >= close {
+            break;
+        }
+        if text(start) == Some(separator) {
+            i = start + 1;
+            continue;
+        }
+        starts.push(start);
+        let mut depth = 0;
+        i = start;
+        while i < close {
+            match tokens[i].kind {
+                TokenKind::Open(_) => {
+                    let end = matching(&tokens, i);
+                    i = end + 1;
+                    if separator == ";" && text(end) == Some("}") {
+                        break;
+                    }
+                }
+                _ if depth == 0 && text(i) == Some(separator) => {
+                    i += 1;
+                    break;
+                }
+                _ => {
+                    if separator == "," {
+                        angle(i, &mut depth);
+                    }
+                    i += 1;
+                }
+            }
+        }
+    }
+    if starts.len() == members.len() {
+        for (start, vis) in starts.into_iter().zip(members) {
+            if private(vis) {
+                exports.push((range.start + tokens[start].range.start, "pub "));
+            }
+        }
+    }
+    exports
+}
+
+/// The index of the token closing the bracket opened by `tokens[open]`, or
+/// the last token when it is never closed.
+fn matching(tokens: &[Token], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.kind {
+            TokenKind::Open(_) => depth += 1,
+            TokenKind::Close(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len().saturating_sub(1)
+}
+
+/// Finds the inner attributes in `content`, like `#![feature(..)]`.
+pub fn inner_attributes(content: &str) -> Vec<Range<usize>> {
+    let tokens = tokens(content);
+    let text = |i: usize| tokens.get(i).map(|token: &Token| &content[token.range.clone()]);
+    let mut attributes = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        if text(i) == Some("#") && text(i + 1) == Some("!") && text(i + 2) == Some("[") {
+            let mut depth = 0;
+            for (j, token) in tokens.iter().enumerate().skip(i + 2) {
+                match token.kind {
+                    TokenKind::Open(_) => depth += 1,
+                    TokenKind::Close(_) => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    attributes.push(tokens[i].range.start..token.range.end);
+                    i = j;
+                    break;
+                }
+            }
+        }
+        i += 1;
+    }
+    attributes
+}
+
+/// Finds where parsing `content` as the statements of a node goes wrong.
+///
+/// The parse errors of `syn` carry no position, so this looks for the longest
+/// prefix of the tokens of the node which can still be completed into valid
+/// statements. The span covers the token following that prefix, or the last
+/// token if the node simply ends too early.
+///
+/// Prefixes ending in a `;` or `}` are bisected first, to find the statement
+/// going wrong in a few attempts, and only the end of that statement is then
+/// tried token by token.
+pub fn parse_error_span(content: &str) -> Span {
+    let tokens = tokens(content);
+    let completes = |end: usize| {
+        let closers = match closers(&tokens[..end]) {
+            Some(closers) => closers,
+            None => return false,
+        };
+        let prefix = &content[..tokens[..end].last().map_or(0, |token| token.range.end)];
+        ["", "0", ";"].iter().any(|fill| {
+            ["", ";"].iter().any(|end| {
+                let src = format!("{{{}{}{}{}\n}}", prefix, fill, closers, end);
+                syn::parse_str::<syn::Block>(&src).is_ok()
+            })
+        })
+    };
+    let boundaries = std::iter::once(0)
+        .chain(tokens.iter().enumerate().filter_map(|(i, token)| match token.kind {
+            TokenKind::Semi | TokenKind::Close('}') if i + 1 < tokens.len() => Some(i + 1),
+            _ => None,
+        }))
+        .collect::<Vec<_>>();
+    // The empty prefix always completes, unlike the whole node
+    let (mut completing, mut failing) = (0, boundaries.len());
+    while failing - completing > 1 {
+        let middle = (completing + failing) / 2;
+        if completes(boundaries[middle]) {
+            completing = middle;
+        } else {
+            failing = middle;
+        }
+    }
+    let start = boundaries[completing];
+    let end = boundaries.get(failing).cloned().unwrap_or(tokens.len());
+    let valid = (start + 1..end)
+        .rev()
+        .take(MAX_PARSE_ATTEMPTS)
+        .find(|&end| completes(end))
+        .unwrap_or(start);
+    let range = match tokens.get(valid).or_else(|| tokens.last()) {
+        Some(token) => token.range.clone(),
+        None => content.len()..content.len(),
+    };
+    Span {
+        start: position(content, range.start),
+        end: position(content, range.end),
+    }
+}
+
+/// The brackets closing every bracket left open by `tokens`, or `None` if they
+/// close a bracket which was never opened.
+fn closers(tokens: &[Token]) -> Option<String> {
+    let mut open = vec![];
+    for token in tokens {
+        match token.kind {
+            TokenKind::Open(close) => open.push(close),
+            TokenKind::Close(close) if open.pop() != Some(close) => return None,
+            _ => {}
+        }
+    }
+    Some(open.iter().rev().collect())
+}
+
+/// The offsets just past every `;` and `}` which is not nested in brackets,
+/// and the end of `content`. These are where a statement can end.
+fn statement_ends(content: &str) -> Vec<usize> {
+    let mut ends = vec![];
+    let mut depth = 0;
+    for token in tokens(content) {
+        match token.kind {
+            TokenKind::Open(_) => depth += 1,
+            TokenKind::Close(close) => {
+                depth -= 1;
+                if depth == 0 && close == '}' {
+                    ends.push(token.range.end);
+                }
+            }
+            TokenKind::Semi if depth == 0 => ends.push(token.range.end),
+            _ => {}
+        }
+    }
+    ends.push(content.len());
+    ends
+}
+
+enum TokenKind {
+    /// An opening bracket, and the bracket closing it
+    Open(char),
+    Close(char),
+    Semi,
+    Other,
+}
+
+struct Token {
+    kind: TokenKind,
+    range: Range<usize>,
+}
+
+/// Finds the line comments in `content`, leaving out anything which only
+/// looks like one, inside a string or a block comment.
+pub fn line_comments(content: &str) -> Vec<Range<usize>> {
+    scan(content).1
+}
+
+/// Splits `content` into tokens, roughly the way Rust would. Punctuation is
+/// split into single characters, and comments are left out.
+fn tokens(content: &str) -> Vec<Token> {
+    scan(content).0
+}
+
+/// The tokens of `content`, along with its line comments.
+fn scan(content: &str) -> (Vec<Token>, Vec<Range<usize>>) {
+    let bytes = content.as_bytes();
+    let is_ident = |i: usize| bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_';
+    let mut tokens = vec![];
+    let mut comments = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let mut kind = TokenKind::Other;
+        match bytes[i] {
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                comments.push(start..i);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let mut nesting = 0;
+                while i < bytes.len() {
+                    if bytes[i..].starts_with(b"/*") {
+                        nesting += 1;
+                        i += 1;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        nesting -= 1;
+                        i += 1;
+                        if nesting == 0 {
+                            i += 1;
+                            break;
+                        }
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            b'r' if (i == 0 || !is_ident(i - 1) || (bytes[i - 1] == b'b' && (i == 1 || !is_ident(i - 2))))
+                && bytes[i + 1..]
+                    .iter()
+                    .find(|&&b| b != b'#')
+                    == Some(&b'"') =>
+            {
+                let hashes = bytes[i + 1..].iter().take_while(|&&b| b == b'#').count();
+                let closing = format!("\"{}", "#".repeat(hashes));
+                i += hashes + 2;
+                while i < bytes.len() && !bytes[i..].starts_with(closing.as_bytes()) {
+                    i += 1;
+                }
+                i += hashes + 1;
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'\'' => {
+                // Either a character literal or a lifetime
+                i += 1;
+                if bytes.get(i) == Some(&b'\\') {
+                    i += 2;
+                    while i < bytes.len() && bytes[i] != b'\'' {
+                        i += 1;
+                    }
+                    i += 1;
+                } else if let Some(c) = content[i..].chars().next() {
+                    if bytes.get(i + c.len_utf8()) == Some(&b'\'') {
+                        i += c.len_utf8() + 1;
+                    } else {
+                        while i < bytes.len() && is_ident(i) {
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            b if b.is_ascii_digit() => {
+                while i < bytes.len()
+                    && (is_ident(i)
+                        || (bytes[i] == b'.'
+                            && bytes.get(i + 1).map(u8::is_ascii_digit) == Some(true)))
+                {
+                    i += 1;
+                }
+            }
+            b if b.is_ascii() && !is_ident(i) => {
+                kind = match b {
+                    b'(' => TokenKind::Open(')'),
+                    b'[' => TokenKind::Open(']'),
+                    b'{' => TokenKind::Open('}'),
+                    b')' | b']' | b'}' => TokenKind::Close(b as char),
+                    b';' => TokenKind::Semi,
+                    _ => TokenKind::Other,
+                };
+                i += 1;
+            }
+            _ => {
+                // Identifiers, including ones with non-ASCII characters
+                while let Some(c) = content[i..].chars().next() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    i += c.len_utf8();
+                }
+                if i == start {
+                    i += content[i..].chars().next().map_or(1, char::len_utf8);
+                }
+            }
+        }
+        tokens.push(Token {
+            kind,
+            range: start..i.min(bytes.len()),
+        });
+    }
+    (tokens, comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> LineColumn {
+        LineColumn { line, column }
+    }
+
+    #[test]
+    fn position_counts_characters() {
+        let src = "let é = \"ü\";\nlet x = 1;";
+        assert_eq!(position(src, 0), at(1, 1));
+        // `=` follows a two byte character
+        assert_eq!(position(src, src.find('=').unwrap()), at(1, 7));
+        assert_eq!(position(src, src.find(';').unwrap()), at(1, 12));
+        assert_eq!(position(src, src.find('x').unwrap()), at(2, 5));
+        assert_eq!(position(src, src.len()), at(2, 11));
+    }
+
+    #[test]
+    fn lookup_maps_back_to_the_node() {
+        let content = "let ü = 1;\nlet y = ü + \"é\";";
+        let second = content.find("let y").unwrap();
+        let mut src = SourceBuilder::new();
+        src.push("fn main() {\n    ");
+        src.push_node(3, content, second..content.len());
+        src.push("\n}\n");
+        let (src, map) = src.finish();
+        assert_eq!(src.lines().nth(1), Some("    let y = ü + \"é\";"));
+
+        // `ü` on the second line of the generated source, as rustc counts
+        // columns, in characters
+        assert_eq!(map.lookup(at(2, 13)), Some((3, at(2, 9))));
+        // The semicolon, after the multi-byte characters
+        assert_eq!(map.lookup(at(2, 20)), Some((3, at(2, 16))));
+        assert_eq!(map.lookup(at(1, 1)), None);
+        assert_eq!(
+            map.lookup_span(at(2, 13), at(2, 14)),
+            Some((
+                3,
+                Span {
+                    start: at(2, 9),
+                    end: at(2, 10),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn lookup_spans_lines() {
+        let content = "let s = \"ä\n  ö\";";
+        let mut src = SourceBuilder::new();
+        src.push("// prelude\n");
+        src.push_node(0, content, 0..content.len());
+        let (_, map) = src.finish();
+        assert_eq!(map.lookup(at(2, 10)), Some((0, at(1, 10))));
+        assert_eq!(map.lookup(at(3, 4)), Some((0, at(2, 4))));
+        assert_eq!(map.lookup_span(at(2, 9), at(3, 6)).map(|(node, _)| node), Some(0));
+    }
+
+    #[test]
+    fn statement_ranges_cover_the_node() {
+        let content = "let a = \"ö;\";\nfn f() {}\na.len()";
+        let block = syn::parse_str::<syn::Block>(&format!("{{{}\n}}", content)).unwrap();
+        let ranges = statement_ranges(content, &block.stmts).unwrap();
+        let texts = ranges
+            .iter()
+            .map(|range| content[range.clone()].trim())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["let a = \"ö;\";", "fn f() {}", "a.len()"]);
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_token() {
+        let span = parse_error_span("let s = \"é\";\nlet = 2;");
+        assert_eq!(span.start, at(2, 5));
+        assert_eq!(span.end, at(2, 6));
+        // Inside of an item, and after a path which only completes later
+        let span = parse_error_span("fn f() {\n    let v = std::vec::Vec::new();\n    v +;\n}\nf()");
+        assert_eq!(span.start, at(3, 8));
+        let span = parse_error_span("let x = 1;\nlet y = (x, 2;\nx");
+        assert_eq!(span.start, at(2, 14));
+    }
+
+    #[test]
+    fn parse_errors_of_long_nodes_are_found() {
+        let statements = "let x = 1;\n".repeat(1000);
+        let span = parse_error_span(&format!("{}let = 2;\n{}", statements, statements));
+        assert_eq!(span.start, at(1001, 5));
+    }
+}
@@ -0,0 +1,79 @@
+// The `/stream` WebSocket.
+//
+// The client sends its `ExecutionParameters` as a text message, and gets an
+// `ExecutionEvent` back for every line of output and node result while the
+// execution runs on its own thread. The socket is closed after `Done`.
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{ws, HttpRequest, HttpResponse};
+use futures::sync::mpsc;
+use shared::*;
+
+use cancel::Cancel;
+use {execute_in, AppState, Execution};
+
+pub fn connect(req: HttpRequest<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    ws::start(req, ExecutionSocket { started: false })
+}
+
+struct ExecutionSocket {
+    started: bool,
+}
+
+impl Actor for ExecutionSocket {
+    type Context = ws::WebsocketContext<Self, AppState>;
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for ExecutionSocket {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Text(text) => {
+                // One execution per connection
+                if self.started {
+                    return;
+                }
+                let parameters = match serde_json::from_str::<ExecutionParameters>(&text) {
+                    Ok(parameters) => parameters,
+                    Err(_) => {
+                        ctx.close(None);
+                        ctx.stop();
+                        return;
+                    }
+                };
+                self.started = true;
+
+                let (sender, receiver) = mpsc::unbounded();
+                let pool = ctx.state().pool.clone();
+                let sessions = ctx.state().sessions.clone();
+                let running = ctx.state().running.clone();
+                std::thread::spawn(move || {
+                    let execution = Execution {
+                        run_dir: None,
+                        parameters,
+                        cancel: Cancel::default(),
+                    };
+                    let events = |event| {
+                        let _ = sender.unbounded_send(event);
+                    };
+                    let response = execute_in(&pool, &sessions, &running, &execution, &events);
+                    events(ExecutionEvent::Done(response));
+                });
+                ctx.add_stream(receiver);
+            }
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+impl StreamHandler<ExecutionEvent, ()> for ExecutionSocket {
+    fn handle(&mut self, event: ExecutionEvent, ctx: &mut Self::Context) {
+        ctx.text(serde_json::to_string(&event).expect("failed to serialize event"));
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(None);
+        ctx.stop();
+    }
+}
@@ -0,0 +1,229 @@
+// How long rustc took for the crate of the nodes, from the report of
+// `cargo build --timings`.
+//
+// The report is an HTML page with the data of every compiled unit embedded as
+// JSON. Nightly cargo splits the time of a unit into sections like `codegen`
+// and `link` with `-Zsection-timings`, stable cargo only has the total. Cargo
+// older than 1.60 writes no report, and leaves the timings out.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct Unit {
+    name: String,
+    /// Seconds
+    duration: f64,
+    sections: Option<Vec<(String, Section)>>,
+}
+
+#[derive(Deserialize)]
+struct Section {
+    start: f64,
+    end: f64,
+}
+
+/// The time rustc spent on a crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CrateTimings {
+    pub compile: Option<Duration>,
+    pub codegen: Option<Duration>,
+    pub link: Option<Duration>,
+}
+
+impl CrateTimings {
+    /// Adds up the timings of crates built one after another.
+    pub fn add(self, other: CrateTimings) -> CrateTimings {
+        fn add(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            }
+        }
+        CrateTimings {
+            compile: add(self.compile, other.compile),
+            codegen: add(self.codegen, other.codegen),
+            link: add(self.link, other.link),
+        }
+    }
+}
+
+/// What cargo can report of a build, found out the first time it is needed.
+static SUPPORT: AtomicUsize = AtomicUsize::new(UNKNOWN);
+const UNKNOWN: usize = 0;
+const NO_REPORT: usize = 1;
+const TOTAL: usize = 2;
+const SECTIONS: usize = 3;
+
+fn support() -> usize {
+    let support = SUPPORT.load(Ordering::SeqCst);
+    if support != UNKNOWN {
+        return support;
+    }
+    let output = |args: &[&str]| {
+        Command::new("cargo")
+            .args(args)
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default()
+    };
+    let support = if !output(&["build", "--help"]).contains("--timings") {
+        NO_REPORT
+    } else if output(&["-V"]).contains("-nightly") {
+        SECTIONS
+    } else {
+        TOTAL
+    };
+    SUPPORT.store(support, Ordering::SeqCst);
+    support
+}
+
+/// Has the `cargo build` of `command` write a timings report, if cargo can.
+pub fn apply(command: &mut Command) -> &mut Command {
+    match support() {
+        SECTIONS => command.arg("--timings").arg("-Zsection-timings"),
+        TOTAL => command.arg("--timings"),
+        _ => command,
+    }
+}
+
+/// A duration of `secs` seconds, of which cargo reports fractions.
+fn duration(secs: f64) -> Duration {
+    let secs = secs.max(0.0);
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+/// Reads the timings of `name` from the report of the last build into
+/// `target_dir` which compiled it. The target directory is shared with other
+/// executioners building at the same time, so only the reports holding `name`
+/// are looked at, and removed, as cargo keeps one for every build.
+pub fn read(target_dir: &Path, name: &str) -> CrateTimings {
+    let reports = std::fs::read_dir(target_dir.join("cargo-timings"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                // `cargo-timing.html` is a copy of the report of whichever
+                // build finished last
+                .filter(|path| {
+                    path.file_name()
+                        .is_some_and(|file| file.to_string_lossy().starts_with("cargo-timing-"))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    // The names of the reports start with the time of the build
+    let mut ours = reports
+        .into_iter()
+        .filter_map(|path| {
+            let unit = units(&path)?.into_iter().rfind(|unit| unit.name == name)?;
+            Some((path, unit))
+        })
+        .collect::<Vec<_>>();
+    ours.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, _) in &ours {
+        let _ = std::fs::remove_file(path);
+    }
+    // A build script of the crate shares its name, and runs before it
+    let unit = match ours.pop() {
+        Some((_, unit)) => unit,
+        None => return CrateTimings::default(),
+    };
+    let section = |name: &str| {
+        let sections = unit.sections.as_ref()?;
+        let (_, section) = sections.iter().find(|(section, _)| section == name)?;
+        Some(duration(section.end - section.start))
+    };
+    CrateTimings {
+        compile: Some(duration(unit.duration)),
+        codegen: section("codegen"),
+        link: section("link"),
+    }
+}
+
+/// The data of the units compiled in the build of the report at `path`.
+fn units(path: &Path) -> Option<Vec<Unit>> {
+    let report = std::fs::read_to_string(path).ok()?;
+    let start = report.find("const UNIT_DATA = ")? + "const UNIT_DATA = ".len();
+    serde_json::Deserializer::from_str(&report[start..])
+        .into_iter::<Vec<Unit>>()
+        .next()?
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(units: &str) -> String {
+        format!(
+            "<html><script>\nconst UNIT_DATA = {};\nconst CONCURRENCY_DATA = [];\n</script></html>",
+            units
+        )
+    }
+
+    #[test]
+    fn reads_the_latest_report_of_the_crate() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let reports = target_dir.path().join("cargo-timings");
+        std::fs::create_dir(&reports).unwrap();
+        let write = |file: &str, units: &str| {
+            std::fs::write(reports.join(file), report(units)).unwrap();
+        };
+        write(
+            "cargo-timing-20240101T100000Z.html",
+            r#"[{"name": "runtree_a", "duration": 9.0}]"#,
+        );
+        write(
+            "cargo-timing-20240101T100001Z.html",
+            r#"[
+                {"name": "serde", "duration": 4.0},
+                {"name": "runtree_a", "duration": 0.1},
+                {"name": "runtree_a", "duration": 2.5, "sections": [
+                    ["frontend", {"start": 0.0, "end": 1.0}],
+                    ["codegen", {"start": 1.0, "end": 2.25}]
+                ]}
+            ]"#,
+        );
+        write(
+            "cargo-timing-20240101T100002Z.html",
+            r#"[{"name": "runtree_b", "duration": 1.0}]"#,
+        );
+        write(
+            "cargo-timing.html",
+            r#"[{"name": "runtree_a", "duration": 5.0}]"#,
+        );
+
+        let timings = read(target_dir.path(), "runtree_a");
+        assert_eq!(timings.compile, Some(Duration::from_millis(2500)));
+        assert_eq!(timings.codegen, Some(Duration::from_millis(1250)));
+        assert_eq!(timings.link, None);
+        // The reports of the crate are gone, those of others are left alone
+        let mut left = std::fs::read_dir(&reports)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            ["cargo-timing-20240101T100002Z.html", "cargo-timing.html"]
+        );
+        let timings = read(target_dir.path(), "runtree_a");
+        assert_eq!(timings.compile, None);
+    }
+
+    #[test]
+    fn missing_reports_leave_the_timings_out() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let timings = read(target_dir.path(), "runtree_a");
+        assert_eq!(timings.compile, None);
+        assert_eq!(timings.codegen, None);
+    }
+
+    #[test]
+    fn fractions_of_seconds_are_kept() {
+        assert_eq!(duration(1.5), Duration::from_millis(1500));
+        assert_eq!(duration(-0.1), Duration::from_secs(0));
+    }
+}
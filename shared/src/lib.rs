@@ -2,22 +2,214 @@
 extern crate serde_derive;
 #[macro_use]
 extern crate failure;
+extern crate serde_json;
+
+use std::fmt;
+
+/// How the result of a node is turned into a string, chosen with a `//: debug`,
+/// `//: pretty` or `//: display` directive in the node.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Format {
+    /// `{:?}`
+    Debug,
+    /// `{:#?}`
+    PrettyDebug,
+    /// `{}`
+    Display,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NodeResult {
-    String(String),
+    String {
+        value: String,
+        format: Format,
+        /// The type of the value, without the paths
+        type_name: String,
+    },
     None,
+    /// Rich output of a value implementing `ReplRender`
+    Html(String),
+    Svg(String),
+    /// A PNG image, encoded as base64
+    Png(String),
+    Markdown(String),
+    Table {
+        columns: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    /// The node panicked. `node` and `location` are where the panic happened,
+    /// which is in another node when calling a function defined there, and
+    /// missing when it happened outside of the nodes.
+    Panic {
+        message: String,
+        node: Option<usize>,
+        location: Option<LineColumn>,
+        backtrace: Option<String>,
+    },
+    /// The time an iteration of a `//: bench` node takes, in nanoseconds.
+    Bench {
+        mean: f64,
+        median: f64,
+        stddev: f64,
+        iterations: u64,
+    },
+    /// A result too long to send at once. The rest of it, up to `full_len`
+    /// bytes, can be fetched from `/output/{handle}`, and turned back into
+    /// the result it was cut from with `TruncatedResult::restore`.
+    Truncated {
+        preview: String,
+        full_len: usize,
+        handle: String,
+        of: TruncatedResult,
+    },
+}
+
+/// What a truncated result was, before it was cut down to its text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TruncatedResult {
+    String { format: Format, type_name: String },
+    Html,
+    Svg,
+    Png,
+    Markdown,
+    /// The text is the table as JSON
+    Table,
+}
+
+impl TruncatedResult {
+    /// The result `text` was cut from, once all of it is fetched.
+    pub fn restore(&self, text: String) -> Option<NodeResult> {
+        Some(match self {
+            TruncatedResult::String { format, type_name } => NodeResult::String {
+                value: text,
+                format: *format,
+                type_name: type_name.clone(),
+            },
+            TruncatedResult::Html => NodeResult::Html(text),
+            TruncatedResult::Svg => NodeResult::Svg(text),
+            TruncatedResult::Png => NodeResult::Png(text),
+            TruncatedResult::Markdown => NodeResult::Markdown(text),
+            TruncatedResult::Table => {
+                let (columns, rows) = serde_json::from_str(&text).ok()?;
+                NodeResult::Table { columns, rows }
+            }
+        })
+    }
+}
+
+/// Where to fetch the rest of a truncated text from, `/output/{handle}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Truncated {
+    pub full_len: usize,
+    pub handle: String,
+}
+
+/// A part of a truncated text, starting `offset` bytes into it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub text: String,
+    pub offset: usize,
+    pub full_len: usize,
+}
+
+/// `None` when the text is not kept around any more.
+pub type ChunkResponse = Option<Chunk>;
+
+/// What a node evaluated to, and what it printed while doing so.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeOutput {
+    pub result: NodeResult,
+    pub stdout: String,
+    /// Set when `stdout` is cut short, as everything printed by the nodes
+    /// together is limited.
+    #[serde(default)]
+    pub stdout_truncated: Option<Truncated>,
+    /// How long the node took to run, in nanoseconds. Missing when it did
+    /// not finish.
+    #[serde(default)]
+    pub time_ns: Option<u64>,
+}
+
+/// The cargo profile the nodes are built with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Profile {
+    Debug,
+    Release,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::Debug
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Profile::Debug => write!(f, "debug"),
+            Profile::Release => write!(f, "release"),
+        }
+    }
+}
+
+/// How the nodes are compiled. Anything left out is up to cargo.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildOptions {
+    pub profile: Profile,
+    /// `0`, `1`, `2`, `3`, `s` or `z`
+    pub opt_level: Option<String>,
+    /// Passed to rustc as `-C target-cpu`, like `native`
+    pub target_cpu: Option<String>,
+    /// Further flags for rustc, separated by spaces
+    pub rustflags: Option<String>,
+    /// `2015`, `2018`, `2021` or `2024`
+    pub edition: Option<String>,
+}
+
+/// How long an execution took, in nanoseconds.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Timing {
+    /// Resolving dependencies and building, as seen from the outside
+    pub build_ns: u64,
+    /// The part of `build_ns` rustc spent on the crate of the nodes, leaving
+    /// out its dependencies
+    pub compile_ns: Option<u64>,
+    /// The code generation and linking parts of `compile_ns`, only reported
+    /// by nightly cargo
+    pub codegen_ns: Option<u64>,
+    pub link_ns: Option<u64>,
+    /// Running the nodes, as seen from the outside
+    pub run_ns: u64,
+    /// The CPU time, user and system, of running the nodes. Missing in
+    /// persistent mode, as the kernel runs for longer than a run.
+    pub run_cpu_ns: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecutionResult {
+    /// Milliseconds, rounded down. Kept for older clients, `timing` has more.
     pub build_time: u32,
+    /// Milliseconds, rounded down. Kept for older clients, `timing` has more.
     pub run_time: u32,
-    pub nodes: Vec<NodeResult>,
+    #[serde(default)]
+    pub timing: Timing,
+    /// The profile the nodes were actually built with.
+    #[serde(default)]
+    pub profile: Profile,
+    pub nodes: Vec<NodeOutput>,
+    /// Everything printed by the nodes, up to the limit of the server.
     pub stdout: String,
+    /// Set when `stdout` is cut short.
+    #[serde(default)]
+    pub stdout_truncated: Option<Truncated>,
+    /// How many executions were ahead of this one in the queue of the server
+    /// when it arrived, if it had to wait for a build directory.
+    #[serde(default)]
+    pub queue_position: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecutionNode {
     pub content: String,
 }
@@ -25,12 +217,134 @@ pub struct ExecutionNode {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecutionParameters {
     pub nodes: Vec<ExecutionNode>,
+    /// Keep the bindings of already evaluated nodes alive between runs, and
+    /// only evaluate nodes which are new or have been edited.
+    #[serde(default)]
+    pub persistent: bool,
+    #[serde(default)]
+    pub build: BuildOptions,
+    /// The id of the session to run in. Without one, the nodes run in
+    /// whichever build directory of the server is free.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// The id to cancel the execution by, as lowercase hex digits. `POST
+    /// /execute` only responds once the execution is done, so the id can be
+    /// chosen up front. Without one, the server makes one up, and sends it in
+    /// the `Started` event of `/stream`.
+    #[serde(default)]
+    pub execution: Option<String>,
+}
+
+/// A named workspace on the server, with a build directory, dependencies and
+/// notebook of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub name: String,
+    /// Seconds since the session last ran anything
+    pub idle_secs: u64,
+}
+
+/// The body of `POST /sessions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewSession {
+    pub name: String,
+}
+
+/// A session with the nodes of its last execution.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionNotebook {
+    pub session: SessionInfo,
+    pub nodes: Vec<ExecutionNode>,
+}
+
+/// `None` when there is no such session.
+pub type SessionResponse = Option<SessionNotebook>;
+
+/// A position in the source of a node. Lines and columns count from 1, and
+/// columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A range in the source of a node, not including `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: LineColumn,
+    pub end: LineColumn,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.start.line, self.start.column)
+    }
+}
+
+/// A replacement proposed by the compiler to fix a diagnostic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub message: String,
+    pub span: Span,
+    pub replacement: String,
+}
+
+/// A message from the compiler. `node` and `span` are missing when it does
+/// not point into the code of a single node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// `error`, `warning`, `note` or `help`
+    pub level: String,
+    pub message: String,
+    pub code: Option<String>,
+    pub node: Option<usize>,
+    pub span: Option<Span>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Phase {
+    Build,
+    Run,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Build => write!(f, "build"),
+            Phase::Run => write!(f, "run"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Limit {
+    Cpu,
+    Memory,
+    FileSize,
+    Processes,
+    Syscall,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Cpu => write!(f, "CPU time"),
+            Limit::Memory => write!(f, "memory"),
+            Limit::FileSize => write!(f, "file size"),
+            Limit::Processes => write!(f, "process count"),
+            Limit::Syscall => write!(f, "allowed syscalls"),
+        }
+    }
 }
 
 #[derive(Debug, Fail, Serialize, Deserialize)]
 pub enum XXError {
     #[fail(
-        display = "Failed to parse node.\n  Error:\n{}\n  Source:\n{}",
+        display = "Failed to parse node {} at {}.\n  Error:\n{}\n  Source:\n{}",
+        node,
+        span,
         error,
         src
     )]
@@ -38,17 +352,91 @@ pub enum XXError {
         src: String,
         error: String,
         node: usize,
+        /// Where in `src` the parse error is
+        span: Span,
     },
     #[fail(
         display = "Build Error.\n  'cargo build' failed with output:\n{}",
         error
     )]
-    BuildError { error: String },
+    BuildError {
+        error: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    #[fail(
+        display = "Dependency Error.\n  Resolving the dependencies failed with output:\n{}",
+        error
+    )]
+    DependencyError { error: String },
     #[fail(
         display = "Run Error.\n  Running the program failed with output:\n{}",
         stderr
     )]
     RunError { stdout: String, stderr: String },
+    #[fail(
+        display = "Result Error.\n  The results of the nodes could not be read:\n{}",
+        error
+    )]
+    ResultError { error: String },
+    #[fail(
+        display = "Timeout.\n  The {} step was killed after {}ms. Output so far:\n{}",
+        phase,
+        elapsed_ms,
+        partial_stdout
+    )]
+    Timeout {
+        phase: Phase,
+        elapsed_ms: u32,
+        partial_stdout: String,
+    },
+    #[fail(
+        display = "Limit Exceeded.\n  The program was stopped for exceeding its {} limit:\n{}",
+        limit,
+        stderr
+    )]
+    LimitExceeded {
+        limit: Limit,
+        stdout: String,
+        stderr: String,
+    },
+    #[fail(
+        display = "Unknown Session.\n  There is no session `{}`, it might have been deleted after being idle.",
+        session
+    )]
+    UnknownSession { session: String },
+    #[fail(
+        display = "Cancelled.\n  The execution was stopped. Output so far:\n{}",
+        partial_stdout
+    )]
+    Cancelled { partial_stdout: String },
+    #[fail(
+        display = "Invalid Execution Id.\n  `{}` is not made of lowercase hex digits, or another execution already has it.",
+        execution
+    )]
+    InvalidExecutionId { execution: String },
+    #[fail(
+        display = "Persistent Without Session.\n  Persistent nodes run in the kernel of a session, create one with `POST /sessions` first."
+    )]
+    PersistentWithoutSession,
 }
 
-pub type ExecutionResponse = Result<ExecutionResult, XXError>;
\ No newline at end of file
+pub type ExecutionResponse = Result<ExecutionResult, XXError>;
+
+/// Sent over the `/stream` WebSocket while an execution is running. The
+/// client sends its `ExecutionParameters`, and the last event is always `Done`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExecutionEvent {
+    /// The id to cancel the execution with at `/cancel/{execution}`, sent
+    /// before anything else.
+    Started { execution: String },
+    /// Waiting for a build directory, behind `position` other executions.
+    /// Sent again whenever the queue moves.
+    Queued { position: usize },
+    /// A line of output from cargo while resolving dependencies or building.
+    Build(String),
+    Stdout(String),
+    Stderr(String),
+    /// The output of a node, as soon as it is known.
+    Node { node: usize, output: NodeOutput },
+    Done(ExecutionResponse),
+}